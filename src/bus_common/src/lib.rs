@@ -0,0 +1,4 @@
+//! Code shared by the service bus canisters.
+
+pub mod record;
+pub mod uuid;
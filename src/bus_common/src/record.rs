@@ -0,0 +1,115 @@
+use candid::{CandidType, Deserialize};
+
+
+/******************************************************/
+//
+//  VERSIONED RECORDS
+//
+//  Stored records are prefixed with a tag byte and their
+//  schema version. Records written before the prefix existed
+//  start with the Candid magic bytes and count as version 0.
+//
+//  record_encode       Encode a record at its current version
+//  record_decode       Decode a record written at any version
+//  record_version      Schema version of an encoded record
+//
+/******************************************************/
+
+const RECORD_TAG: u8 = 0xB5;
+const CANDID_MAGIC: &[u8] = b"DIDL";
+
+pub trait VersionedRecord: CandidType + for<'de> Deserialize<'de> {
+    /// Version written by `record_encode`. Bump it whenever the encoding of
+    /// the record changes in a way Candid cannot decode on its own, and
+    /// handle the previous version in `upgrade`.
+    const VERSION: u8;
+
+    /// Decodes the Candid payload of a record stored at an older `version`.
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, String>;
+}
+
+pub fn record_encode<T: VersionedRecord>(record: &T) -> Vec<u8> {
+    let payload = candid::encode_one(record).expect("Could not encode the record");
+
+    let mut bytes = Vec::with_capacity(payload.len() + 2);
+    bytes.push(RECORD_TAG);
+    bytes.push(T::VERSION);
+    bytes.extend(payload);
+    bytes
+}
+
+pub fn record_version(bytes: &[u8]) -> Result<u8, String> {
+    match bytes {
+        [RECORD_TAG, version, ..] => Ok(*version),
+        _ if bytes.starts_with(CANDID_MAGIC) => Ok(0),
+        _ => Err("The record has an unknown encoding".to_string()),
+    }
+}
+
+pub fn record_decode<T: VersionedRecord>(bytes: &[u8]) -> Result<T, String> {
+    let version = record_version(bytes)?;
+    let payload = if version == 0 { bytes } else { &bytes[2..] };
+
+    if version == T::VERSION {
+        return candid::decode_one(payload).map_err(|err| err.to_string());
+    }
+
+    if version > T::VERSION {
+        return Err(format!(
+            "The record was written at version {} but this build reads up to version {}",
+            version,
+            T::VERSION,
+        ));
+    }
+
+    T::upgrade(version, payload)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(CandidType, Deserialize, Debug, PartialEq)]
+    struct Record {
+        name: String,
+        count: Option<u64>,
+    }
+
+    impl VersionedRecord for Record {
+        const VERSION: u8 = 2;
+
+        fn upgrade(version: u8, payload: &[u8]) -> Result<Self, String> {
+            match version {
+                0 | 1 => candid::decode_one(payload).map_err(|err| err.to_string()),
+                _ => Err(format!("Unknown record version {}", version)),
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_at_the_current_version() {
+        let record = Record { name: "topic".to_string(), count: Some(3) };
+        let bytes = record_encode(&record);
+
+        assert_eq!(record_version(&bytes), Ok(2));
+        assert_eq!(record_decode::<Record>(&bytes), Ok(record));
+    }
+
+    #[test]
+    fn reads_unprefixed_records_as_version_0() {
+        let bytes = candid::encode_one(Record { name: "topic".to_string(), count: None }).unwrap();
+
+        assert_eq!(record_version(&bytes), Ok(0));
+        assert_eq!(record_decode::<Record>(&bytes).unwrap().name, "topic");
+    }
+
+    #[test]
+    fn rejects_newer_and_unknown_encodings() {
+        let mut bytes = record_encode(&Record { name: "topic".to_string(), count: None });
+        bytes[1] = 3;
+
+        assert!(record_decode::<Record>(&bytes).is_err());
+        assert!(record_decode::<Record>(b"garbage").is_err());
+    }
+}
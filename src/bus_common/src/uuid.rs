@@ -0,0 +1,78 @@
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use std::{cell::RefCell, time::Duration};
+use uuid_by_string::generate_uuid::{generate_uuid};
+
+
+/******************************************************/
+//
+//  UUID
+//
+//  seed_uuid_generator     Seed the generator from raw_rand
+//  schedule_uuid_seed      Seed the generator after init/upgrade
+//  create_uuid             Generate a new identifier
+//
+/******************************************************/
+
+thread_local! {
+    static RNG: RefCell<Option<ChaCha20Rng>> = const { RefCell::new(None) };
+    static COUNTER: RefCell<u64> = const { RefCell::new(0) };
+}
+
+/// Seeds the identifier generator with 32 bytes from the management
+/// canister's `raw_rand`.
+pub async fn seed_uuid_generator() -> Result<(), String> {
+    let (bytes, ) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| format!("Could not fetch randomness: {:?} {}", code, msg))?;
+
+    let seed: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| "raw_rand returned a seed of unexpected length".to_string())?;
+
+    RNG.with(|r| *r.borrow_mut() = Some(ChaCha20Rng::from_seed(seed)));
+    Ok(())
+}
+
+/// Inter-canister calls are not allowed in `init` and `post_upgrade`, so the
+/// seeding is deferred to a zero-delay timer.
+pub fn schedule_uuid_seed() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            if let Err(err) = seed_uuid_generator().await {
+                ic_cdk::print(err);
+            }
+        })
+    });
+}
+
+/// Returns a random (version 4) UUID. Until the generator is seeded the
+/// identifier is derived from the canister ID, the time and a counter, which
+/// keeps it unique within a message execution.
+pub fn create_uuid() -> String {
+    let mut bytes = [0u8; 16];
+
+    let seeded = RNG.with(|r| match r.borrow_mut().as_mut() {
+        Some(rng) => {
+            rng.fill_bytes(&mut bytes);
+            true
+        }
+        None => false,
+    });
+
+    if !seeded {
+        let count = COUNTER.with(|c| {
+            let mut c = c.borrow_mut();
+            *c += 1;
+            *c
+        });
+
+        return generate_uuid(&format!("{}-{}-{}", ic_cdk::id(), ic_cdk::api::time(), count));
+    }
+
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
@@ -53,7 +53,7 @@ pub async fn subscription(subscription_id: String) -> Subscriber {
                 name: "".to_string(),
                 description: "".to_string(),
                 callback: "".to_string(),
-                canister_id: "".to_string(),
+                canister_id: Principal::anonymous(),
                 topic: "".to_string(),
                 namespace: "".to_string(),
                 active: true,
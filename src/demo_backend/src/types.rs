@@ -1,4 +1,4 @@
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize, Principal};
 
 
 /******************************************************/
@@ -34,7 +34,7 @@ pub struct CallSubscribersResponse {
 #[derive(CandidType, Deserialize)]
 pub struct Subscriber {
     pub id: String,
-    pub canister_id: String,
+    pub canister_id: Principal,
     pub callback: String,
     pub name: String,
     pub description: String,
@@ -46,7 +46,7 @@ pub struct Subscriber {
 #[derive(CandidType, Deserialize)]
 pub struct Subscribers {
     pub id: String,
-    pub canister_id: String,
+    pub canister_id: Principal,
     pub callback: String,
     pub name: String,
     pub description: String,
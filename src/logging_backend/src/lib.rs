@@ -4,8 +4,7 @@ use std::collections::VecDeque;
 use std::sync::RwLock;
 use serde::{Deserialize, Serialize};
 
-
-use uuid_by_string::generate_uuid::{generate_uuid};
+use bus_common::uuid::{create_uuid, schedule_uuid_seed};
 
 
 static mut LOCKVD: RwLock<VecDeque::<String>> = RwLock::new(VecDeque::<String>::new());
@@ -38,7 +37,7 @@ pub async fn log(logtype: u8, origin: String, message: String, data: String) ->
     let ts = ic_cdk::api::time().to_string();
 
     let entry = LogEntry {
-        log_id: generate_uuid(&ts),
+        log_id: create_uuid(),
         log_type: logtype,
         log_origin: origin,
         log_canister: submitter_principal.to_string(),
@@ -133,10 +132,15 @@ fn deserialize_message(msg: String) -> LogEntry {
 
 /******************************************************/
 //
-//  UPGRADE
+//  INIT AND UPGRADE
 //
 /******************************************************/
 
+#[ic_cdk_macros::init]
+fn init() {
+    schedule_uuid_seed();
+}
+
 #[ic_cdk_macros::pre_upgrade]
 fn pre_upgrade() {
 
@@ -144,5 +148,5 @@ fn pre_upgrade() {
 
 #[ic_cdk_macros::post_upgrade]
 fn post_upgrade() {
-
+    schedule_uuid_seed();
 }
\ No newline at end of file
@@ -0,0 +1,367 @@
+use candid::Principal;
+use ic_cdk::api::call::CallResult;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use crate::types::{BatchSettings, BatchStatus, Envelope, EnvelopeResult, QueueConfig, SubscriberCache};
+use crate::circuit::{circuit_admits, circuit_result, circuit_started};
+use crate::cycles::{cycles_call, cycles_record};
+use crate::{
+    delivery_release, delivery_reserve, delivery_start, queue_config_get,
+    BATCH_SETTINGS, SUBSCRIBER_DATA_CACHE,
+};
+
+const MAX_BATCH_SIZE: u64 = 500;
+const MAX_WAIT_SECS: u64 = 60 * 60;
+
+/// Messages collected for one subscriber. Beyond this the subscriber counts
+/// as full and the consumer leaves new messages in the queue.
+const BATCH_MAX_PENDING: usize = 10_000;
+
+/// Times a message can fail in a batch the subscriber replied to before it
+/// is dropped.
+const BATCH_MAX_ATTEMPTS: u32 = 5;
+
+/// A message with the number of times the subscriber failed it.
+type PendingEnvelope = (Envelope, u32);
+
+struct PendingBatch {
+    envelopes: VecDeque<PendingEnvelope>,
+    waiting_since: u64,
+}
+
+thread_local! {
+    static PENDING: RefCell<HashMap<String, PendingBatch>> = RefCell::new(HashMap::new());
+}
+
+
+/******************************************************/
+//
+//  BATCH DELIVERY
+//
+//  Subscribers that opt in get their messages in batches,
+//  one call with a vector of envelopes instead of one call
+//  per message. A batch takes one delivery slot, so the in
+//  flight limits count calls, not messages.
+//
+//  A batch the subscriber rejects goes back to the front of
+//  its pending batch and counts toward the circuit breaker.
+//  Messages the subscriber fails in its reply are put back
+//  too, and dropped after BATCH_MAX_ATTEMPTS. A reply that
+//  fails every message counts toward the circuit as well.
+//  Messages put back may take a batch past the pending
+//  limit for a while.
+//
+//  Pending batches live on the heap and are lost on upgrade
+//  like the queue itself.
+//
+//  batch_subscribe     Opt a subscriber in, by the subscriber
+//                      canister or a controller
+//  batch_unsubscribe   Back to one call per message
+//  batch_status        Settings and pending messages
+//
+/******************************************************/
+
+#[ic_cdk_macros::update]
+fn batch_subscribe(subscriber_id: String, settings: BatchSettings) -> Result<String, String> {
+    batch_caller_check(&subscriber_id)?;
+
+    if settings.callback.is_empty() {
+        return Err("callback cannot be empty".to_string());
+    }
+
+    if settings.max_batch_size == 0 || settings.max_batch_size > MAX_BATCH_SIZE {
+        return Err(format!("max_batch_size must be between 1 and {}", MAX_BATCH_SIZE));
+    }
+
+    if settings.max_wait_secs > MAX_WAIT_SECS {
+        return Err(format!("max_wait_secs can be at most {}", MAX_WAIT_SECS));
+    }
+
+    BATCH_SETTINGS.with(|p| p.borrow_mut().insert(subscriber_id, settings));
+    Ok("Success: The subscriber receives batches".to_string())
+}
+
+/// Messages already collected are still delivered, one call each.
+#[ic_cdk_macros::update]
+fn batch_unsubscribe(subscriber_id: String) -> Result<String, String> {
+    batch_caller_check(&subscriber_id)?;
+
+    match BATCH_SETTINGS.with(|p| p.borrow_mut().remove(&subscriber_id)) {
+        Some(_) => Ok("Success: The subscriber receives single messages".to_string()),
+        None => Err("The subscriber does not receive batches".to_string()),
+    }
+}
+
+#[ic_cdk_macros::query]
+fn batch_status(subscriber_id: Option<String>) -> Vec<BatchStatus> {
+    let ids: BTreeSet<String> = match subscriber_id {
+        Some(id) => BTreeSet::from([id]),
+        None => {
+            let mut ids: BTreeSet<String> = BATCH_SETTINGS.with(|p| p.borrow().iter().map(|(k, _)| k).collect());
+            PENDING.with(|p| ids.extend(p.borrow().keys().cloned()));
+            ids
+        },
+    };
+
+    PENDING.with(|p| {
+        let pending = p.borrow();
+
+        ids.into_iter()
+            .map(|id| {
+                let batch = pending.get(&id);
+
+                BatchStatus {
+                    settings: batch_settings(&id),
+                    pending: batch.map_or(0, |b| b.envelopes.len() as u64),
+                    waiting_since: batch.map(|b| b.waiting_since),
+                    subscriber_id: id,
+                }
+            })
+            .collect()
+    })
+}
+
+pub fn batch_settings(subscriber_id: &str) -> Option<BatchSettings> {
+    BATCH_SETTINGS.with(|p| p.borrow().get(&subscriber_id.to_string()))
+}
+
+pub fn batch_full(subscriber_id: &str) -> bool {
+    batch_room(subscriber_id) == 0
+}
+
+/// Messages that can still be added to the subscriber's pending batch.
+pub fn batch_room(subscriber_id: &str) -> usize {
+    PENDING.with(|p| {
+        BATCH_MAX_PENDING.saturating_sub(p.borrow().get(subscriber_id).map_or(0, |b| b.envelopes.len()))
+    })
+}
+
+/// Adds a message to the pending batch of each target.
+pub fn batch_add(targets: &[SubscriberCache], envelope: &Envelope) {
+    let now = ic_cdk::api::time();
+
+    PENDING.with(|p| {
+        let mut pending = p.borrow_mut();
+
+        for target in targets.iter() {
+            pending.entry(target.id.clone())
+                .or_insert_with(|| PendingBatch { envelopes: VecDeque::new(), waiting_since: now })
+                .envelopes
+                .push_back((envelope.clone(), 0));
+        }
+    });
+}
+
+/// Puts messages of a failed batch back in front of the pending batch, in
+/// their order.
+fn batch_requeue(subscriber_id: &str, envelopes: Vec<PendingEnvelope>) {
+    if envelopes.is_empty() {
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+
+    PENDING.with(|p| {
+        let mut pending = p.borrow_mut();
+        let batch = pending.entry(subscriber_id.to_string())
+            .or_insert_with(|| PendingBatch { envelopes: VecDeque::new(), waiting_since: now });
+
+        for envelope in envelopes.into_iter().rev() {
+            batch.envelopes.push_front(envelope);
+        }
+    });
+}
+
+/// Runs on every consumer tick. Sends every batch that is full or has
+/// waited long enough, as far as the in flight limits allow. Batches of
+/// subscribers that opted out are sent one message per call.
+pub fn batch_tick(config: &QueueConfig) {
+    let now = ic_cdk::api::time();
+    let ids: Vec<String> = PENDING.with(|p| p.borrow().keys().cloned().collect());
+
+    for subscriber_id in ids.iter() {
+        let target = match SUBSCRIBER_DATA_CACHE.with(|p| p.borrow().get(subscriber_id)) {
+            Some(target) => target,
+            None => {
+                PENDING.with(|p| p.borrow_mut().remove(subscriber_id));
+                continue;
+            },
+        };
+
+        match batch_settings(subscriber_id) {
+            Some(settings) => batch_flush(config, &target, &settings, now),
+            None => batch_unbatch(config, &target),
+        }
+    }
+}
+
+fn batch_flush(config: &QueueConfig, target: &SubscriberCache, settings: &BatchSettings, now: u64) {
+    let max_wait_ns = settings.max_wait_secs.saturating_mul(1_000_000_000);
+
+    loop {
+        let due = PENDING.with(|p| {
+            p.borrow().get(&target.id).is_some_and(|b| {
+                b.envelopes.len() as u64 >= settings.max_batch_size
+                    || now.saturating_sub(b.waiting_since) >= max_wait_ns
+            })
+        });
+
+        // A half open circuit lets one batch through as its probe
+        if !due || !circuit_admits(&target.id) || !delivery_reserve(config, std::slice::from_ref(target)) {
+            return;
+        }
+
+        let envelopes = batch_take(&target.id, settings.max_batch_size as usize);
+
+        circuit_started(&target.id);
+        ic_cdk::spawn(batch_execute(target.id.clone(), target.canister_id, settings.callback.clone(), envelopes));
+    }
+}
+
+fn batch_unbatch(config: &QueueConfig, target: &SubscriberCache) {
+    while circuit_admits(&target.id) && delivery_reserve(config, std::slice::from_ref(target)) {
+        match batch_take(&target.id, 1).pop() {
+            Some((envelope, _)) => delivery_start(&envelope, vec![target.clone()]),
+            None => {
+                delivery_release(&target.id);
+                return;
+            },
+        }
+    }
+}
+
+/// Takes up to `size` envelopes off the front of a pending batch. What is
+/// left keeps its waiting time, so it is sent on the next tick if the wait
+/// is already over.
+fn batch_take(subscriber_id: &str, size: usize) -> Vec<PendingEnvelope> {
+    PENDING.with(|p| {
+        let mut pending = p.borrow_mut();
+
+        let batch = match pending.get_mut(subscriber_id) {
+            Some(batch) => batch,
+            None => return Vec::new(),
+        };
+
+        let size = size.min(batch.envelopes.len());
+        let envelopes: Vec<PendingEnvelope> = batch.envelopes.drain(..size).collect();
+
+        if batch.envelopes.is_empty() {
+            pending.remove(subscriber_id);
+        }
+
+        envelopes
+    })
+}
+
+/// Puts back what failed, see the banner, and records the outcome with the
+/// circuit breaker.
+async fn batch_execute(
+    subscriber_id: String,
+    canister_id: Principal,
+    callback: String,
+    pending: Vec<PendingEnvelope>,
+) {
+    let envelopes: Vec<Envelope> = pending.iter().map(|(envelope, _)| envelope.clone()).collect();
+    let (result, cycles): (CallResult<(Vec<EnvelopeResult>, )>, u128) =
+        cycles_call(canister_id, &callback, (&envelopes, )).await;
+
+    cycles_record(&subscriber_id, &envelopes, cycles);
+    delivery_release(&subscriber_id);
+
+    let delivered = match result {
+        Ok((results, )) => {
+            let failed: HashSet<String> = results.into_iter().filter(|r| r.result.is_err()).map(|r| r.id).collect();
+            let (retry, dropped) = batch_failed(&pending, &failed);
+
+            for (envelope, _) in dropped.iter() {
+                ic_cdk::print(format!("Subscriber {} failed message {} {} times, dropped", subscriber_id, envelope.id, BATCH_MAX_ATTEMPTS));
+            }
+
+            if !retry.is_empty() {
+                ic_cdk::print(format!("Subscriber {} failed {} messages, retrying", subscriber_id, retry.len()));
+            }
+
+            batch_requeue(&subscriber_id, retry);
+            failed.len() < pending.len()
+        },
+        Err((code, message)) => {
+            ic_cdk::print(format!("Batch of {} to {} failed: {:?}: {}", pending.len(), canister_id, code, message));
+            batch_requeue(&subscriber_id, pending);
+            false
+        },
+    };
+
+    circuit_result(&queue_config_get(), &subscriber_id, delivered);
+}
+
+/// Splits the messages the subscriber failed into those to retry, with one
+/// more failure counted, and those out of attempts.
+fn batch_failed(pending: &[PendingEnvelope], failed: &HashSet<String>) -> (Vec<PendingEnvelope>, Vec<PendingEnvelope>) {
+    pending
+        .iter()
+        .filter(|(envelope, _)| failed.contains(&envelope.id))
+        .map(|(envelope, failures)| (envelope.clone(), failures + 1))
+        .partition(|(_, failures)| *failures < BATCH_MAX_ATTEMPTS)
+}
+
+/// The subscriber's own canister, as cached from the registry, or a
+/// controller.
+fn batch_caller_check(subscriber_id: &str) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+
+    if ic_cdk::api::is_controller(&caller) {
+        return Ok(());
+    }
+
+    match SUBSCRIBER_DATA_CACHE.with(|p| p.borrow().get(&subscriber_id.to_string())) {
+        Some(subscriber) if subscriber.canister_id == caller => Ok(()),
+        Some(_) => Err("Only the subscriber's canister can change its batch settings".to_string()),
+        None => Err("The subscriber is not known to the queue".to_string()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(id: &str) -> Envelope {
+        Envelope {
+            id: id.to_string(),
+            topic: "orders".to_string(),
+            value: String::new(),
+            publisher: None,
+            queued_at: 0,
+        }
+    }
+
+    fn ids(envelopes: &[PendingEnvelope]) -> Vec<(&str, u32)> {
+        envelopes.iter().map(|(e, failures)| (e.id.as_str(), *failures)).collect()
+    }
+
+    #[test]
+    fn failed_messages_are_retried_until_out_of_attempts() {
+        let pending = vec![
+            (envelope("a"), 0),
+            (envelope("b"), BATCH_MAX_ATTEMPTS - 2),
+            (envelope("c"), BATCH_MAX_ATTEMPTS - 1),
+            (envelope("d"), 0),
+        ];
+        let failed: HashSet<String> = ["a", "b", "c"].iter().map(|id| id.to_string()).collect();
+
+        let (retry, dropped) = batch_failed(&pending, &failed);
+
+        assert_eq!(ids(&retry), vec![("a", 1), ("b", BATCH_MAX_ATTEMPTS - 1)]);
+        assert_eq!(ids(&dropped), vec![("c", BATCH_MAX_ATTEMPTS)]);
+    }
+
+    #[test]
+    fn unknown_result_ids_are_ignored() {
+        let pending = vec![(envelope("a"), 0)];
+        let failed: HashSet<String> = HashSet::from(["x".to_string()]);
+
+        let (retry, dropped) = batch_failed(&pending, &failed);
+
+        assert!(retry.is_empty() && dropped.is_empty());
+    }
+}
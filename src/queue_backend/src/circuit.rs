@@ -0,0 +1,455 @@
+use ic_cdk::api::call::CallResult;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use crate::types::{CircuitState, CircuitStatus, Envelope, QueueConfig, SubscriberCache};
+use crate::batch::{batch_add, batch_room, batch_settings};
+use crate::{delivery_reserve, delivery_start, registry_canister, SUBSCRIBER_DATA_CACHE};
+
+/// Messages held for a subscriber with an open circuit. The oldest ones are
+/// dropped beyond this.
+const CIRCUIT_MAX_BUFFERED: usize = 10_000;
+
+struct Circuit {
+    state: CircuitState,
+    failures: u64,
+    opened_at: Option<u64>,
+    probe_in_flight: bool,
+    buffered: VecDeque<Envelope>,
+    dropped: u64,
+    updated_at: u64,
+}
+
+impl Circuit {
+    fn new(now: u64) -> Self {
+        Circuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            opened_at: None,
+            probe_in_flight: false,
+            buffered: VecDeque::new(),
+            dropped: 0,
+            updated_at: now,
+        }
+    }
+
+    /// Buffers a message, dropping the oldest one when the buffer is full.
+    fn hold(&mut self, envelope: &Envelope) {
+        if self.buffered.len() >= CIRCUIT_MAX_BUFFERED {
+            self.buffered.pop_front();
+            self.dropped += 1;
+        }
+
+        self.buffered.push_back(envelope.clone());
+    }
+
+    /// Applies the outcome of a delivery call. Returns whether the state
+    /// changed.
+    fn record(&mut self, delivered: bool, threshold: u64, now: u64) -> bool {
+        let before = self.state;
+
+        if delivered {
+            self.state = CircuitState::Closed;
+            self.failures = 0;
+            self.opened_at = None;
+        } else {
+            self.failures += 1;
+
+            let opens = match self.state {
+                CircuitState::Closed => self.failures >= threshold,
+                CircuitState::HalfOpen => true,
+                CircuitState::Open => false,
+            };
+
+            if opens {
+                self.state = CircuitState::Open;
+                self.opened_at = Some(now);
+            }
+        }
+
+        self.probe_in_flight = false;
+        self.updated_at = now;
+
+        self.state != before
+    }
+
+    /// Moves an open circuit to half open once it has cooled down. Returns
+    /// whether it did.
+    fn cool_down(&mut self, now: u64, cooldown_ns: u64) -> bool {
+        let cooled_down = self.opened_at.is_some_and(|at| now.saturating_sub(at) >= cooldown_ns);
+
+        if self.state != CircuitState::Open || !cooled_down {
+            return false;
+        }
+
+        self.state = CircuitState::HalfOpen;
+        self.updated_at = now;
+        true
+    }
+
+    /// A closed circuit lets deliveries through once its buffer is flushed,
+    /// so messages keep their order. A half open one lets one probe through.
+    fn admits(&self) -> bool {
+        match self.state {
+            CircuitState::Closed => self.buffered.is_empty(),
+            CircuitState::HalfOpen => self.buffered.is_empty() && !self.probe_in_flight,
+            CircuitState::Open => false,
+        }
+    }
+
+    fn idle(&self) -> bool {
+        self.state == CircuitState::Closed && self.failures == 0 && self.buffered.is_empty()
+    }
+
+    fn status(&self, subscriber_id: &str) -> CircuitStatus {
+        CircuitStatus {
+            subscriber_id: subscriber_id.to_string(),
+            state: self.state,
+            failures: self.failures,
+            opened_at: self.opened_at,
+            buffered: self.buffered.len() as u64,
+            dropped: self.dropped,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+thread_local! {
+    static CIRCUITS: RefCell<HashMap<String, Circuit>> = RefCell::new(HashMap::new());
+}
+
+
+/******************************************************/
+//
+//  CIRCUIT BREAKER
+//
+//  A subscriber whose calls fail `circuit_threshold` times
+//  in a row gets an open circuit: its messages are buffered
+//  instead of delivered. After `circuit_cooldown_secs` the
+//  circuit is half open and one message is sent as a probe.
+//  A successful probe closes the circuit and the buffer is
+//  flushed, a failed one opens it again.
+//
+//  Only rejected calls count as failures. A callback that
+//  returns an error was reached, so retrying it is not
+//  what the circuit is for. A batch reply that fails every
+//  message counts as a failure, see `batch.rs`.
+//
+//  Circuits live on the heap and start closed after an
+//  upgrade.
+//
+//  circuit_status      State of every subscriber that is not
+//                      healthy, or of one subscriber
+//
+/******************************************************/
+
+#[ic_cdk_macros::query]
+fn circuit_status(subscriber_id: Option<String>) -> Vec<CircuitStatus> {
+    CIRCUITS.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(id, _)| subscriber_id.as_ref().is_none_or(|s| s == *id))
+            .map(|(id, circuit)| circuit.status(id))
+            .collect()
+    })
+}
+
+pub fn circuit_admits(subscriber_id: &str) -> bool {
+    CIRCUITS.with(|p| p.borrow().get(subscriber_id).is_none_or(|c| c.admits()))
+}
+
+/// Buffers a message for subscribers whose circuit did not admit it.
+pub fn circuit_hold(targets: &[SubscriberCache], envelope: &Envelope) {
+    let now = ic_cdk::api::time();
+
+    CIRCUITS.with(|p| {
+        let mut circuits = p.borrow_mut();
+
+        for target in targets.iter() {
+            circuits.entry(target.id.clone()).or_insert_with(|| Circuit::new(now)).hold(envelope);
+        }
+    });
+}
+
+/// Marks the delivery a half open circuit admitted as its probe.
+pub fn circuit_started(subscriber_id: &str) {
+    CIRCUITS.with(|p| {
+        if let Some(circuit) = p.borrow_mut().get_mut(subscriber_id) {
+            if circuit.state == CircuitState::HalfOpen {
+                circuit.probe_in_flight = true;
+            }
+        }
+    });
+}
+
+/// Records the outcome of a delivery call.
+pub fn circuit_result(config: &QueueConfig, subscriber_id: &str, delivered: bool) {
+    let now = ic_cdk::api::time();
+
+    let changed = CIRCUITS.with(|p| {
+        let mut circuits = p.borrow_mut();
+
+        if delivered && !circuits.contains_key(subscriber_id) {
+            return None;
+        }
+
+        let circuit = circuits.entry(subscriber_id.to_string()).or_insert_with(|| Circuit::new(now));
+
+        let status = circuit
+            .record(delivered, config.circuit_threshold, now)
+            .then(|| circuit.status(subscriber_id));
+
+        if circuit.idle() {
+            circuits.remove(subscriber_id);
+        }
+
+        status
+    });
+
+    if let Some(status) = changed {
+        circuit_report(status);
+    }
+}
+
+/// Runs on every consumer tick, before new messages are taken off the
+/// queue: moves cooled down circuits to half open, sends their probe and
+/// flushes the buffers of closed circuits as far as capacity allows.
+pub fn circuit_tick(config: &QueueConfig) {
+    let now = ic_cdk::api::time();
+    let cooldown_ns = config.circuit_cooldown_secs.saturating_mul(1_000_000_000);
+
+    let mut half_opened: Vec<CircuitStatus> = Vec::new();
+
+    let pending: Vec<String> = CIRCUITS.with(|p| {
+        let mut circuits = p.borrow_mut();
+
+        for (id, circuit) in circuits.iter_mut() {
+            if circuit.cool_down(now, cooldown_ns) {
+                half_opened.push(circuit.status(id));
+            }
+        }
+
+        circuits.iter()
+            .filter(|(_, c)| c.state != CircuitState::Open && !c.probe_in_flight && !c.buffered.is_empty())
+            .map(|(id, _)| id.clone())
+            .collect()
+    });
+
+    for status in half_opened.into_iter() {
+        circuit_report(status);
+    }
+
+    for subscriber_id in pending.iter() {
+        circuit_flush(config, subscriber_id);
+    }
+}
+
+/// Delivers buffered messages while the subscriber has capacity, or a single
+/// one as the probe of a half open circuit. Subscribers that receive batches
+/// get as much of the buffer added to their batch as it has room for, and
+/// the batch is then sent, or used as the probe, like any other. The buffer
+/// of a subscriber that left the cache is dropped.
+fn circuit_flush(config: &QueueConfig, subscriber_id: &str) {
+    let target = match SUBSCRIBER_DATA_CACHE.with(|p| p.borrow().get(&subscriber_id.to_string())) {
+        Some(target) => target,
+        None => {
+            CIRCUITS.with(|p| p.borrow_mut().remove(subscriber_id));
+            return;
+        },
+    };
+
+    if batch_settings(subscriber_id).is_some() {
+        let room = batch_room(subscriber_id);
+
+        let buffered: Vec<Envelope> = CIRCUITS.with(|p| {
+            p.borrow_mut().get_mut(subscriber_id).map_or(Vec::new(), |c| {
+                let count = room.min(c.buffered.len());
+                c.buffered.drain(..count).collect()
+            })
+        });
+
+        for envelope in buffered.iter() {
+            batch_add(std::slice::from_ref(&target), envelope);
+        }
+        return;
+    }
+
+    loop {
+        let envelope = CIRCUITS.with(|p| {
+            let mut circuits = p.borrow_mut();
+            let circuit = circuits.get_mut(subscriber_id)?;
+
+            if circuit.state == CircuitState::Open || circuit.probe_in_flight || circuit.buffered.is_empty() {
+                return None;
+            }
+
+            if !delivery_reserve(config, std::slice::from_ref(&target)) {
+                return None;
+            }
+
+            circuit.buffered.pop_front()
+        });
+
+        match envelope {
+            Some(envelope) => delivery_start(&envelope, vec![target.clone()]),
+            None => break,
+        }
+    }
+}
+
+/// Tells the registry, so the subscriber view shows the state. Failures are
+/// only logged, the next change is reported anyway.
+fn circuit_report(status: CircuitStatus) {
+    let registry = match registry_canister() {
+        Ok(registry) => registry,
+        Err(_) => return,
+    };
+
+    ic_cdk::spawn(async move {
+        let result: CallResult<(Result<String, String>, )> =
+            ic_cdk::call(registry, "subscriber_circuit_report", (status, )).await;
+
+        match result {
+            Ok((Err(err), )) => ic_cdk::print(format!("The registry rejected a circuit report: {}", err)),
+            Err((code, message)) => ic_cdk::print(format!("Could not report a circuit: {:?}: {}", code, message)),
+            _ => {},
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: u64 = 3;
+    const COOLDOWN_NS: u64 = 30_000_000_000;
+
+    fn envelope(id: usize) -> Envelope {
+        Envelope {
+            id: id.to_string(),
+            topic: "orders".to_string(),
+            value: String::new(),
+            publisher: None,
+            queued_at: 0,
+        }
+    }
+
+    fn opened(now: u64) -> Circuit {
+        let mut circuit = Circuit::new(0);
+
+        for _ in 0..THRESHOLD {
+            circuit.record(false, THRESHOLD, now);
+        }
+
+        circuit
+    }
+
+    #[test]
+    fn opens_after_threshold_failures_in_a_row() {
+        let mut circuit = Circuit::new(0);
+
+        assert!(!circuit.record(false, THRESHOLD, 1));
+        assert!(!circuit.record(false, THRESHOLD, 2));
+        assert_eq!(circuit.state, CircuitState::Closed);
+        assert!(circuit.admits());
+
+        assert!(circuit.record(false, THRESHOLD, 3));
+        assert_eq!(circuit.state, CircuitState::Open);
+        assert_eq!(circuit.opened_at, Some(3));
+        assert!(!circuit.admits());
+    }
+
+    #[test]
+    fn a_success_resets_the_failure_count() {
+        let mut circuit = Circuit::new(0);
+
+        circuit.record(false, THRESHOLD, 1);
+        circuit.record(false, THRESHOLD, 2);
+        assert!(!circuit.record(true, THRESHOLD, 3));
+        assert!(circuit.idle());
+
+        circuit.record(false, THRESHOLD, 4);
+        circuit.record(false, THRESHOLD, 5);
+        assert_eq!(circuit.state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn failures_while_open_do_not_restart_the_cooldown() {
+        let mut circuit = opened(10);
+
+        assert!(!circuit.record(false, THRESHOLD, 20));
+        assert_eq!(circuit.state, CircuitState::Open);
+        assert_eq!(circuit.opened_at, Some(10));
+    }
+
+    #[test]
+    fn half_opens_after_the_cooldown() {
+        let mut circuit = opened(10);
+
+        assert!(!circuit.cool_down(10 + COOLDOWN_NS - 1, COOLDOWN_NS));
+        assert_eq!(circuit.state, CircuitState::Open);
+
+        assert!(circuit.cool_down(10 + COOLDOWN_NS, COOLDOWN_NS));
+        assert_eq!(circuit.state, CircuitState::HalfOpen);
+        assert!(circuit.admits());
+
+        // Only open circuits cool down
+        assert!(!circuit.cool_down(10 + 2 * COOLDOWN_NS, COOLDOWN_NS));
+        assert!(!Circuit::new(0).cool_down(COOLDOWN_NS, COOLDOWN_NS));
+    }
+
+    #[test]
+    fn a_half_open_circuit_admits_one_probe() {
+        let mut circuit = opened(0);
+        circuit.cool_down(COOLDOWN_NS, COOLDOWN_NS);
+
+        circuit.probe_in_flight = true;
+        assert!(!circuit.admits());
+    }
+
+    #[test]
+    fn a_successful_probe_closes_the_circuit() {
+        let mut circuit = opened(0);
+        circuit.cool_down(COOLDOWN_NS, COOLDOWN_NS);
+        circuit.probe_in_flight = true;
+
+        assert!(circuit.record(true, THRESHOLD, COOLDOWN_NS + 1));
+        assert_eq!(circuit.state, CircuitState::Closed);
+        assert_eq!((circuit.failures, circuit.opened_at, circuit.probe_in_flight), (0, None, false));
+    }
+
+    #[test]
+    fn a_failed_probe_opens_the_circuit_again() {
+        let mut circuit = opened(0);
+        circuit.cool_down(COOLDOWN_NS, COOLDOWN_NS);
+        circuit.probe_in_flight = true;
+
+        assert!(circuit.record(false, THRESHOLD, COOLDOWN_NS + 1));
+        assert_eq!(circuit.state, CircuitState::Open);
+        assert_eq!(circuit.opened_at, Some(COOLDOWN_NS + 1));
+        assert!(!circuit.probe_in_flight);
+    }
+
+    #[test]
+    fn a_closed_circuit_with_a_buffer_holds_deliveries_back() {
+        let mut circuit = Circuit::new(0);
+        circuit.hold(&envelope(0));
+
+        assert!(!circuit.admits());
+        assert!(!circuit.idle());
+    }
+
+    #[test]
+    fn the_buffer_drops_the_oldest_messages_beyond_the_cap() {
+        let mut circuit = opened(0);
+
+        for id in 0..CIRCUIT_MAX_BUFFERED + 2 {
+            circuit.hold(&envelope(id));
+        }
+
+        assert_eq!(circuit.buffered.len(), CIRCUIT_MAX_BUFFERED);
+        assert_eq!(circuit.dropped, 2);
+        assert_eq!(circuit.buffered.front().map(|e| e.id.as_str()), Some("2"));
+        assert_eq!(circuit.status("s").dropped, 2);
+    }
+}
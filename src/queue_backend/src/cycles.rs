@@ -0,0 +1,328 @@
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::Principal;
+use ic_cdk::api::call::{CallResult, RejectionCode};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use crate::types::{CyclesUsage, CyclesUsageKind, CyclesUsageRecord, CyclesUsageRequest, Envelope};
+use crate::{caller_is_controller, CYCLES_USAGE};
+
+/// Fees of a 13 node application subnet, as listed in the cycles cost table
+/// of the IC documentation.
+const CALL_FEE: u128 = 260_000;
+const CALL_BYTE_FEE: u128 = 1_000;
+/// Paid again for executing the reply.
+const MESSAGE_FEE: u128 = 590_000;
+/// 4 cycles per 10 instructions.
+const INSTRUCTION_FEE_PER_10: u128 = 4;
+
+const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+const RETENTION_HOURS: u64 = 90 * 24;
+
+thread_local! {
+    static PRUNED_HOUR: RefCell<u64> = const { RefCell::new(0) };
+}
+
+
+/******************************************************/
+//
+//  CYCLES ACCOUNTING
+//
+//  Every delivery call is priced from the fee schedule:
+//  the call and the bytes sent and received, the execution
+//  of the reply, and the instructions this canister spent
+//  sending the call and handling the reply. The cost is
+//  added to the subscriber and, split evenly over the
+//  delivered messages, to their topics and publishers.
+//  Balance deltas cannot be used, since deliveries overlap.
+//
+//  The numbers are estimates from the fee constants below,
+//  not measured charges. They follow the relative cost of
+//  topics, publishers and subscribers, but do not add up
+//  to the balance change when fees or the subnet differ.
+//
+//  Usage is kept per hour for 90 days. Messages put in the
+//  queue with fifo_producer have no publisher and only
+//  count for their topic and subscriber.
+//
+//  cycles_usage    Usage over a time window
+//
+/******************************************************/
+
+/// Sums the whole window per key, or one entry per hour and key. Long
+/// windows with `hourly` should be narrowed by kind or key.
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn cycles_usage(request: CyclesUsageRequest) -> Vec<CyclesUsage> {
+    let (from_hour, to_hour) = usage_hours(&request);
+
+    if from_hour >= to_hour {
+        return Vec::new();
+    }
+
+    CYCLES_USAGE.with(|p| usage_sum(p.borrow().range(hour_prefix(from_hour)..hour_prefix(to_hour)), &request))
+}
+
+/// Every hour that overlaps the window, including partial hours at either
+/// end.
+fn usage_hours(request: &CyclesUsageRequest) -> (u64, u64) {
+    (request.from / HOUR_NS, request.to.div_ceil(HOUR_NS))
+}
+
+fn usage_sum(records: impl Iterator<Item = (String, CyclesUsageRecord)>, request: &CyclesUsageRequest) -> Vec<CyclesUsage> {
+    let (from_hour, to_hour) = usage_hours(request);
+    let mut usage: BTreeMap<(u64, String), CyclesUsage> = BTreeMap::new();
+
+    for (stored_key, record) in records {
+        let (hour, kind, key) = match usage_key_parse(&stored_key) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+
+        if hour < from_hour || hour >= to_hour {
+            continue;
+        }
+
+        if request.kind.is_some_and(|k| k != kind) || request.key.as_ref().is_some_and(|k| *k != key) {
+            continue;
+        }
+
+        let (from, to) = match request.hourly {
+            true => (hour * HOUR_NS, (hour + 1) * HOUR_NS),
+            false => (from_hour * HOUR_NS, to_hour * HOUR_NS),
+        };
+
+        let entry = usage.entry((from, format!("{}/{}", kind_name(kind), key))).or_insert(CyclesUsage {
+            kind,
+            key,
+            from,
+            to,
+            cycles: 0,
+            messages: 0,
+        });
+
+        entry.cycles += record.cycles;
+        entry.messages += record.messages;
+    }
+
+    usage.into_values().collect()
+}
+
+/// Calls a canister like `ic_cdk::call` and returns the estimated cost of
+/// the call along with its result.
+pub async fn cycles_call<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+    canister_id: Principal,
+    method: &str,
+    args: T,
+) -> (CallResult<R>, u128) {
+    let instructions = ic_cdk::api::performance_counter(0);
+    let request = candid::utils::encode_args(args).expect("Failed to encode arguments.");
+    let request_bytes = request.len();
+    let sent = ic_cdk::api::performance_counter(0).saturating_sub(instructions);
+
+    let response = ic_cdk::api::call::call_raw(canister_id, method, request, 0).await;
+
+    let response_bytes = match &response {
+        Ok(bytes) => bytes.len(),
+        Err((_, message)) => message.len(),
+    };
+
+    let result = response.and_then(|bytes| {
+        candid::utils::decode_args(&bytes)
+            .map_err(|err| (RejectionCode::CanisterError, format!("failed to decode the response: {}", err)))
+    });
+
+    // The counter starts over with the reply
+    let instructions = sent + ic_cdk::api::performance_counter(0);
+
+    let cost = CALL_FEE
+        + CALL_BYTE_FEE * (request_bytes + response_bytes) as u128
+        + MESSAGE_FEE
+        + instructions as u128 * INSTRUCTION_FEE_PER_10 / 10;
+
+    (result, cost)
+}
+
+/// Adds the cost of one delivery call to the subscriber, and to the topics
+/// and publishers of the messages it carried.
+pub fn cycles_record(subscriber_id: &str, envelopes: &[Envelope], cycles: u128) {
+    if envelopes.is_empty() {
+        return;
+    }
+
+    let now_hour = ic_cdk::api::time() / HOUR_NS;
+    let count = envelopes.len() as u128;
+
+    let mut usage: HashMap<String, CyclesUsageRecord> = HashMap::new();
+
+    let subscriber = usage.entry(usage_key(now_hour, CyclesUsageKind::Subscriber, subscriber_id)).or_default();
+    subscriber.cycles += cycles;
+    subscriber.messages += envelopes.len() as u64;
+
+    for (index, envelope) in envelopes.iter().enumerate() {
+        // The first message takes what does not divide evenly
+        let share = cycles / count + if index == 0 { cycles % count } else { 0 };
+
+        let mut keys = vec![usage_key(now_hour, CyclesUsageKind::Topic, &envelope.topic)];
+
+        if let Some(publisher) = envelope.publisher {
+            keys.push(usage_key(now_hour, CyclesUsageKind::Publisher, &publisher.to_text()));
+        }
+
+        for key in keys.into_iter() {
+            let record = usage.entry(key).or_default();
+            record.cycles += share;
+            record.messages += 1;
+        }
+    }
+
+    CYCLES_USAGE.with(|p| {
+        let mut map = p.borrow_mut();
+
+        for (key, added) in usage.into_iter() {
+            let mut record = map.get(&key).unwrap_or_default();
+            record.cycles += added.cycles;
+            record.messages += added.messages;
+            map.insert(key, record);
+        }
+    });
+
+    cycles_prune(now_hour);
+}
+
+/// Removes hours past the retention, once per hour.
+fn cycles_prune(now_hour: u64) {
+    if PRUNED_HOUR.with(|p| *p.borrow() == now_hour) {
+        return;
+    }
+
+    PRUNED_HOUR.with(|p| *p.borrow_mut() = now_hour);
+
+    let expired = hour_prefix(now_hour.saturating_sub(RETENTION_HOURS));
+
+    CYCLES_USAGE.with(|p| {
+        let mut map = p.borrow_mut();
+        let keys: Vec<String> = map.range(..expired).map(|(k, _)| k).collect();
+
+        for key in keys.iter() {
+            map.remove(key);
+        }
+    });
+}
+
+/// Keys start with the zero padded hour, so they sort by time.
+fn hour_prefix(hour: u64) -> String {
+    format!("{:010}/", hour)
+}
+
+fn usage_key(hour: u64, kind: CyclesUsageKind, key: &str) -> String {
+    format!("{}{}/{}", hour_prefix(hour), kind_name(kind), key)
+}
+
+/// Topics may contain slashes, so the key is everything after the kind.
+fn usage_key_parse(stored_key: &str) -> Option<(u64, CyclesUsageKind, String)> {
+    let mut parts = stored_key.splitn(3, '/');
+    let hour = parts.next()?.parse().ok()?;
+
+    let kind = match parts.next()? {
+        "topic" => CyclesUsageKind::Topic,
+        "publisher" => CyclesUsageKind::Publisher,
+        "subscriber" => CyclesUsageKind::Subscriber,
+        _ => return None,
+    };
+
+    Some((hour, kind, parts.next()?.to_string()))
+}
+
+fn kind_name(kind: CyclesUsageKind) -> &'static str {
+    match kind {
+        CyclesUsageKind::Topic => "topic",
+        CyclesUsageKind::Publisher => "publisher",
+        CyclesUsageKind::Subscriber => "subscriber",
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE_NS: u64 = 60 * 1_000_000_000;
+
+    fn request(from: u64, to: u64, hourly: bool) -> CyclesUsageRequest {
+        CyclesUsageRequest { from, to, kind: None, key: None, hourly }
+    }
+
+    fn records() -> Vec<(String, CyclesUsageRecord)> {
+        vec![
+            (usage_key(10, CyclesUsageKind::Topic, "orders/eu"), CyclesUsageRecord { cycles: 100, messages: 1 }),
+            (usage_key(10, CyclesUsageKind::Subscriber, "billing"), CyclesUsageRecord { cycles: 100, messages: 1 }),
+            (usage_key(11, CyclesUsageKind::Topic, "orders/eu"), CyclesUsageRecord { cycles: 30, messages: 2 }),
+            (usage_key(12, CyclesUsageKind::Topic, "orders/eu"), CyclesUsageRecord { cycles: 5, messages: 1 }),
+        ]
+    }
+
+    fn sums(usage: &[CyclesUsage]) -> Vec<(&str, u64, u64, u128, u64)> {
+        usage.iter().map(|u| (u.key.as_str(), u.from / HOUR_NS, u.to / HOUR_NS, u.cycles, u.messages)).collect()
+    }
+
+    #[test]
+    fn a_window_within_one_hour_selects_that_hour() {
+        let from = 11 * HOUR_NS + 10 * MINUTE_NS;
+        let usage = usage_sum(records().into_iter(), &request(from, from + 20 * MINUTE_NS, false));
+
+        assert_eq!(usage_hours(&request(from, from + 20 * MINUTE_NS, false)), (11, 12));
+        assert_eq!(sums(&usage), vec![("orders/eu", 11, 12, 30, 2)]);
+    }
+
+    #[test]
+    fn partial_hours_at_both_ends_are_included() {
+        let usage = usage_sum(
+            records().into_iter(),
+            &request(10 * HOUR_NS + 59 * MINUTE_NS, 12 * HOUR_NS + MINUTE_NS, false),
+        );
+
+        assert_eq!(sums(&usage), vec![("billing", 10, 13, 100, 1), ("orders/eu", 10, 13, 135, 4)]);
+    }
+
+    #[test]
+    fn a_window_ending_on_an_hour_boundary_excludes_that_hour() {
+        assert_eq!(usage_hours(&request(10 * HOUR_NS, 12 * HOUR_NS, false)), (10, 12));
+        assert_eq!(usage_hours(&request(10 * HOUR_NS, 12 * HOUR_NS + 1, false)), (10, 13));
+
+        let usage = usage_sum(records().into_iter(), &request(11 * HOUR_NS, 12 * HOUR_NS, false));
+        assert_eq!(sums(&usage), vec![("orders/eu", 11, 12, 30, 2)]);
+    }
+
+    #[test]
+    fn an_empty_window_selects_nothing() {
+        let (from_hour, to_hour) = usage_hours(&request(11 * HOUR_NS, 11 * HOUR_NS, false));
+        assert!(from_hour >= to_hour);
+
+        assert!(usage_sum(records().into_iter(), &request(11 * HOUR_NS, 11 * HOUR_NS, false)).is_empty());
+    }
+
+    #[test]
+    fn hourly_keeps_every_hour_and_adds_up_to_the_sum() {
+        let hourly = usage_sum(records().into_iter(), &request(10 * HOUR_NS, 13 * HOUR_NS, true));
+        let summed = usage_sum(records().into_iter(), &request(10 * HOUR_NS, 13 * HOUR_NS, false));
+
+        assert_eq!(sums(&hourly), vec![
+            ("billing", 10, 11, 100, 1),
+            ("orders/eu", 10, 11, 100, 1),
+            ("orders/eu", 11, 12, 30, 2),
+            ("orders/eu", 12, 13, 5, 1),
+        ]);
+        assert_eq!(sums(&summed), vec![("billing", 10, 13, 100, 1), ("orders/eu", 10, 13, 135, 4)]);
+    }
+
+    #[test]
+    fn kind_and_key_filters_apply() {
+        let mut by_kind = request(10 * HOUR_NS, 13 * HOUR_NS, false);
+        by_kind.kind = Some(CyclesUsageKind::Subscriber);
+
+        let mut by_key = request(10 * HOUR_NS, 13 * HOUR_NS, false);
+        by_key.key = Some("orders/eu".to_string());
+
+        assert_eq!(sums(&usage_sum(records().into_iter(), &by_kind)), vec![("billing", 10, 13, 100, 1)]);
+        assert_eq!(sums(&usage_sum(records().into_iter(), &by_key)), vec![("orders/eu", 10, 13, 135, 4)]);
+    }
+}
@@ -3,18 +3,17 @@ use ic_cdk::init;
 use std::vec::Vec;
 use std::{borrow::Cow};
 use candid::{CandidType, Decode, Encode, Principal};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::sync::RwLock;
 use serde::{Deserialize, Serialize};
 use ic_stable_structures::{
     Storable, storable::Bound,
-    DefaultMemoryImpl, 
     BTreeMap,
     StableBTreeMap,
+    StableCell,
     memory_manager::MemoryId,
-    memory_manager::MemoryManager,
-    memory_manager::VirtualMemory,
 };
+use ic_cdk::api::call::CallResult;
 use ic_cdk_timers::TimerId;
 use std::{
     cell::RefCell,
@@ -26,17 +25,53 @@ use types::{
     Message, Subscribers, Topics, 
     CanisterIds, Idcache,
     SubscriberCache, CanisterSettings,
-
+    ListArgs, Page, ConfigDocument,
+    InitArgs, QueueSettings, QueueConfig, QueueConfigUpdate,
+    InFlightStatus, SubscriberInFlight,
+    QueuedMessage, IntakeError, IntakeBatchMode, IntakeBatchError, QueueDepth, TopicDepth, PublisherDepth,
+    RateLimit, Envelope, BatchSettings, CyclesUsageRecord,
+};
+use memory::{
+    memory_get, memory_in_use, Memory,
+    LEGACY_MEMORY_IDS, LEGACY_MEMORY_WHITELIST, LEGACY_MEMORY_CANISTER_SETTINGS,
+    MEMORY_WHITELIST, MEMORY_SUBSCRIBER_CACHE, MEMORY_SUBSCRIBER_DATA_CACHE,
+    MEMORY_CANISTER_SETTINGS, MEMORY_SCHEMA_VERSION, MEMORY_CONFIG_VERSION,
+    MEMORY_QUEUE_SETTINGS, MEMORY_QUEUE_CONFIG, MEMORY_RATE_LIMITS, MEMORY_BATCH_SETTINGS,
+    MEMORY_CYCLES_USAGE,
 };
+use rate_limit::{rate_limit_refund, rate_limit_take};
+use circuit::{circuit_admits, circuit_hold, circuit_result, circuit_started, circuit_tick};
+use batch::{batch_add, batch_full, batch_settings, batch_tick};
+use cycles::{cycles_call, cycles_record};
+use bus_common::uuid::{create_uuid, schedule_uuid_seed};
 
 mod types;
-
-const MAX_CHUNK_SIZE: usize = 250;
-const CACHE_TTL_NS: u64 = 60000000000;
+mod memory;
+mod rate_limit;
+mod circuit;
+mod batch;
+mod cycles;
+
+#[cfg(test)]
+mod tests;
+
+pub(crate) const DEFAULT_CHUNK_SIZE: u64 = 250;
+const MAX_CHUNK_SIZE: u64 = 1000;
+pub(crate) const DEFAULT_CACHE_TTL_SECS: u64 = 60;
+pub(crate) const DEFAULT_MAX_CONCURRENCY: u64 = 200;
+pub(crate) const DEFAULT_MAX_PER_SUBSCRIBER: u64 = 20;
+const MAX_CONCURRENCY: u64 = 2000;
+pub(crate) const DEFAULT_MAX_DEPTH: u64 = 100_000;
+pub(crate) const DEFAULT_MAX_TOPIC_DEPTH: u64 = 20_000;
+pub(crate) const DEFAULT_MAX_PUBLISHER_DEPTH: u64 = 10_000;
+pub(crate) const DEFAULT_CIRCUIT_THRESHOLD: u64 = 5;
+pub(crate) const DEFAULT_CIRCUIT_COOLDOWN_SECS: u64 = 60;
+const MAX_INTAKE_BATCH: usize = 1000;
 
 static INITIAL_CANISTER_BALANCE: AtomicU64 = AtomicU64::new(0);
 static CYCLES_USED: AtomicU64 = AtomicU64::new(0);
-static MIN_INTERVAL_SECS: u64 = 10;
+pub(crate) static DEFAULT_INTERVAL_SECS: u64 = 10;
+static MIN_INTERVAL_SECS: u64 = 1;
 
 static mut LOCKCD: RwLock<VecDeque::<String>> = RwLock::new(VecDeque::<String>::new());
 
@@ -47,64 +82,399 @@ static mut LOCKCD: RwLock<VecDeque::<String>> = RwLock::new(VecDeque::<String>::
 //
 /******************************************************/
 
+/// Without init args the registry has to be pushed through the canister
+/// settings before the subscriber cache can be filled.
 #[init]
-pub fn init() -> () {
-    start_with_interval_secs(MIN_INTERVAL_SECS);
+pub fn init(args: Option<InitArgs>) {
+    schema_version_set(SCHEMA_CURRENT);
+
+    if let Some(args) = args {
+        init_args_apply(args);
+    }
+
+    consumer_timer_start(queue_config_get().interval_secs);
+    config_sync_schedule();
+    schedule_uuid_seed();
 }
  
+/// Upgrades without init args keep the stored settings.
 #[ic_cdk_macros::post_upgrade]
-fn post_upgrade() {
-    start_with_interval_secs(MIN_INTERVAL_SECS);
+fn post_upgrade(args: Option<InitArgs>) {
+    layout_migrate();
+    migrations_run();
+
+    if let Some(args) = args {
+        init_args_apply(args);
+    }
+
+    consumer_timer_start(queue_config_get().interval_secs);
+    config_sync_schedule();
+    schedule_uuid_seed();
+}
+
+/// Traps on invalid values, which rejects the whole install or upgrade.
+fn init_args_apply(args: InitArgs) {
+    let mut settings = queue_settings();
+    settings.registry_canister = Some(args.registry_canister);
+    settings.logging_canister = args.logging_canister.or(settings.logging_canister);
+
+    let mut config = queue_config_get();
+    config.interval_secs = args.interval_secs.unwrap_or(config.interval_secs);
+    config.chunk_size = args.chunk_size.unwrap_or(config.chunk_size);
+
+    if let Err(err) = init_args_check(&args).and_then(|_| queue_config_check(&config)) {
+        ic_cdk::trap(&format!("Invalid init args: {}", err));
+    }
+
+    QUEUE_SETTINGS.with(|p| p.borrow_mut().set(settings)).expect("Could not store the queue settings");
+    queue_config_store(config);
+}
+
+fn init_args_check(args: &InitArgs) -> Result<(), String> {
+    if args.registry_canister == Principal::anonymous() {
+        return Err("registry_canister cannot be the anonymous principal".to_string());
+    }
+
+    if args.logging_canister == Some(Principal::anonymous()) {
+        return Err("logging_canister cannot be the anonymous principal".to_string());
+    }
+
+    Ok(())
+}
+
+#[ic_cdk_macros::query]
+fn queue_settings() -> QueueSettings {
+    QUEUE_SETTINGS.with(|p| p.borrow().get().clone())
+}
+
+/// The registry from the init args, or for deployments installed without
+/// them, the one pushed with the canister settings.
+fn registry_canister() -> Result<Principal, String> {
+    queue_settings().registry_canister
+        .or_else(|| canister_settings_get("registry_backend".to_string()).map(|s| s.canister_id))
+        .ok_or_else(|| "The registry canister is not configured, pass registry_canister in the init args".to_string())
+}
+
+
+/// Guard for admin endpoints.
+fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("Only a controller of the queue can call this method".to_string())
+    }
 }
 
 
 /******************************************************/
 //
-//  MEMORY MANAGER
+//  QUEUE CONFIG
+//
+//  queue_config        Get the consumer settings
+//  queue_config_set    Change the consumer settings
 //
 /******************************************************/
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn queue_config() -> QueueConfig {
+    queue_config_get()
+}
 
-thread_local! {
-    static MEM_WHITELIST: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+/// Takes effect on the next consumer tick. A new interval replaces the
+/// running timer; a stopped timer stays stopped.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn queue_config_set(update: QueueConfigUpdate) -> Result<QueueConfig, String> {
+    let mut config = queue_config_get();
+    let interval_changed = update.interval_secs.is_some_and(|secs| secs != config.interval_secs);
 
-    static WHITELIST: RefCell<StableBTreeMap<String, CanisterIds, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEM_WHITELIST.with(|m| m.borrow().get(MemoryId::new(0))),
-        )
-    );    
+    if let Some(interval_secs) = update.interval_secs {
+        config.interval_secs = interval_secs;
+    }
 
-    static MEM_SUBSCRIBER_CACHE: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    if let Some(chunk_size) = update.chunk_size {
+        config.chunk_size = chunk_size;
+    }
 
-    static SUBSCRIBER_CACHE: RefCell<BTreeMap<String, Idcache, Memory>> = RefCell::new(
-        BTreeMap::init(
-            MEM_SUBSCRIBER_CACHE.with(|m| m.borrow().get(MemoryId::new(1))),
-        )
-    );       
+    if let Some(cache_ttl_secs) = update.cache_ttl_secs {
+        config.cache_ttl_secs = cache_ttl_secs;
+    }
 
-    static MEM_SUBSCRIBER_DATA_CACHE: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    if let Some(max_concurrency) = update.max_concurrency {
+        config.max_concurrency = max_concurrency;
+    }
 
-    static SUBSCRIBER_DATA_CACHE: RefCell<BTreeMap<String, SubscriberCache, Memory>> = RefCell::new(
-        BTreeMap::init(
-            MEM_SUBSCRIBER_DATA_CACHE.with(|m| m.borrow().get(MemoryId::new(2))),
-        )
+    if let Some(max_per_subscriber) = update.max_per_subscriber {
+        config.max_per_subscriber = max_per_subscriber;
+    }
+
+    if let Some(max_depth) = update.max_depth {
+        config.max_depth = max_depth;
+    }
+
+    if let Some(max_topic_depth) = update.max_topic_depth {
+        config.max_topic_depth = max_topic_depth;
+    }
+
+    if let Some(max_publisher_depth) = update.max_publisher_depth {
+        config.max_publisher_depth = max_publisher_depth;
+    }
+
+    if let Some(circuit_threshold) = update.circuit_threshold {
+        config.circuit_threshold = circuit_threshold;
+    }
+
+    if let Some(circuit_cooldown_secs) = update.circuit_cooldown_secs {
+        config.circuit_cooldown_secs = circuit_cooldown_secs;
+    }
+
+    queue_config_check(&config)?;
+    queue_config_store(config.clone());
+
+    if interval_changed && consumer_timer_running() {
+        consumer_timer_start(config.interval_secs);
+    }
+
+    Ok(config)
+}
+
+fn queue_config_get() -> QueueConfig {
+    QUEUE_CONFIG.with(|p| p.borrow().get().clone())
+}
+
+fn queue_config_store(config: QueueConfig) {
+    QUEUE_CONFIG.with(|p| p.borrow_mut().set(config)).expect("Could not store the queue config");
+}
+
+fn queue_config_check(config: &QueueConfig) -> Result<(), String> {
+    if config.interval_secs < MIN_INTERVAL_SECS {
+        return Err(format!("interval_secs must be at least {}", MIN_INTERVAL_SECS));
+    }
+
+    if config.chunk_size == 0 || config.chunk_size > MAX_CHUNK_SIZE {
+        return Err(format!("chunk_size must be between 1 and {}", MAX_CHUNK_SIZE));
+    }
+
+    if config.cache_ttl_secs == 0 {
+        return Err("cache_ttl_secs must be at least 1".to_string());
+    }
+
+    if config.max_concurrency == 0 || config.max_concurrency > MAX_CONCURRENCY {
+        return Err(format!("max_concurrency must be between 1 and {}", MAX_CONCURRENCY));
+    }
+
+    if config.max_per_subscriber == 0 || config.max_per_subscriber > config.max_concurrency {
+        return Err("max_per_subscriber must be between 1 and max_concurrency".to_string());
+    }
+
+    if config.max_depth == 0 {
+        return Err("max_depth must be at least 1".to_string());
+    }
+
+    if config.max_topic_depth == 0 || config.max_topic_depth > config.max_depth {
+        return Err("max_topic_depth must be between 1 and max_depth".to_string());
+    }
+
+    if config.max_publisher_depth == 0 || config.max_publisher_depth > config.max_depth {
+        return Err("max_publisher_depth must be between 1 and max_depth".to_string());
+    }
+
+    if config.circuit_threshold == 0 {
+        return Err("circuit_threshold must be at least 1".to_string());
+    }
+
+    if config.circuit_cooldown_secs == 0 {
+        return Err("circuit_cooldown_secs must be at least 1".to_string());
+    }
+
+    Ok(())
+}
+
+
+/******************************************************/
+//
+//  MEMORY MANAGER
+//
+/******************************************************/
+
+thread_local! {
+    static WHITELIST: RefCell<StableBTreeMap<String, CanisterIds, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_WHITELIST))
+    );
+
+    static SUBSCRIBER_CACHE: RefCell<BTreeMap<String, Idcache, Memory>> = RefCell::new(
+        BTreeMap::init(memory_get(MEMORY_SUBSCRIBER_CACHE))
     );
 
-    static MEM_CANISTER_SETTINGS: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    static SUBSCRIBER_DATA_CACHE: RefCell<BTreeMap<String, SubscriberCache, Memory>> = RefCell::new(
+        BTreeMap::init(memory_get(MEMORY_SUBSCRIBER_DATA_CACHE))
+    );
 
     static CANISTER_SETTINGS: RefCell<BTreeMap<String, CanisterSettings, Memory>> = RefCell::new(
-        BTreeMap::init(
-            MEM_CANISTER_SETTINGS.with(|m| m.borrow().get(MemoryId::new(3))),
-        )
+        BTreeMap::init(memory_get(MEMORY_CANISTER_SETTINGS))
+    );
+
+    static SCHEMA_VERSION: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(memory_get(MEMORY_SCHEMA_VERSION), 0)
+            .expect("Could not initialize the schema version")
+    );
+
+    static CONFIG_VERSION: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(memory_get(MEMORY_CONFIG_VERSION), 0)
+            .expect("Could not initialize the config version")
+    );
+
+    static QUEUE_SETTINGS: RefCell<StableCell<QueueSettings, Memory>> = RefCell::new(
+        StableCell::init(memory_get(MEMORY_QUEUE_SETTINGS), QueueSettings::default())
+            .expect("Could not initialize the queue settings")
+    );
+
+    static QUEUE_CONFIG: RefCell<StableCell<QueueConfig, Memory>> = RefCell::new(
+        StableCell::init(memory_get(MEMORY_QUEUE_CONFIG), QueueConfig::default())
+            .expect("Could not initialize the queue config")
+    );
+
+    static RATE_LIMITS: RefCell<StableBTreeMap<String, RateLimit, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_RATE_LIMITS))
+    );
+
+    static BATCH_SETTINGS: RefCell<StableBTreeMap<String, BatchSettings, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_BATCH_SETTINGS))
+    );
+
+    static CYCLES_USAGE: RefCell<StableBTreeMap<String, CyclesUsageRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_CYCLES_USAGE))
     );
 
     static COUNTER: RefCell<u32> = RefCell::new(0);
-    static TIMER_IDS: RefCell<Vec<TimerId>> = RefCell::new(Vec::new());
+    static CONSUMER_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    static IN_FLIGHT: RefCell<u64> = const { RefCell::new(0) };
+    static IN_FLIGHT_BY_SUBSCRIBER: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+    static DEPTH_BY_TOPIC: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+    static DEPTH_BY_PUBLISHER: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+    static CACHE_FETCHED_AT: RefCell<u64> = const { RefCell::new(0) };
+}
+
+
+/******************************************************/
+//
+//  MIGRATION
+//
+//  layout_migrate          Copy data from the legacy memory layout
+//  migrations_run          Apply the migrations newer than the
+//                          stored schema version
+//  canister_ids_migrate    Re-encode text canister IDs as principals
+//  records_rewrite         Store every record at its current version
+//
+/******************************************************/
+
+const SCHEMA_PRINCIPAL_IDS: u64 = 1;
+const SCHEMA_VERSIONED_RECORDS: u64 = 2;
+const SCHEMA_CURRENT: u64 = SCHEMA_VERSIONED_RECORDS;
+
+fn schema_version_set(version: u64) {
+    SCHEMA_VERSION.with(|p| p.borrow_mut().set(version)).expect("Could not store the schema version");
+}
+
+/// Copies the whitelist and canister settings written under the legacy memory
+/// layout to the current memory IDs.
+/// The legacy managers each kept a private copy of the bucket table, so
+/// buckets the stored table reports as free can still hold legacy data;
+/// everything is read before the first current structure is created.
+fn layout_migrate() {
+    if memory_in_use(MEMORY_SCHEMA_VERSION) || !LEGACY_MEMORY_IDS.iter().any(|id| memory_in_use(*id)) {
+        return;
+    }
+
+    let whitelist: Vec<(String, CanisterIds)> = legacy_entries(LEGACY_MEMORY_WHITELIST);
+    let settings: Vec<(String, CanisterSettings)> = legacy_entries(LEGACY_MEMORY_CANISTER_SETTINGS);
+
+    WHITELIST.with(|p| {
+        let mut map = p.borrow_mut();
+
+        for (topic, canister_ids) in whitelist {
+            map.insert(topic, canister_ids);
+        }
+    });
+
+    CANISTER_SETTINGS.with(|p| {
+        let mut map = p.borrow_mut();
+
+        for (canister_name, settings) in settings {
+            map.insert(canister_name, settings);
+        }
+    });
+
+    // The legacy layout predates the schema version, every migration applies
+    schema_version_set(0);
+}
+
+fn legacy_entries<V: Storable>(id: MemoryId) -> Vec<(String, V)> {
+    if !memory_in_use(id) {
+        return Vec::new();
+    }
+
+    StableBTreeMap::<String, V, Memory>::load(memory_get(id)).iter().collect()
+}
+
+fn migrations_run() {
+    let version = SCHEMA_VERSION.with(|p| *p.borrow().get());
+
+    if version < SCHEMA_PRINCIPAL_IDS {
+        canister_ids_migrate();
+        schema_version_set(SCHEMA_PRINCIPAL_IDS);
+    }
+
+    if version < SCHEMA_VERSIONED_RECORDS {
+        records_rewrite();
+        schema_version_set(SCHEMA_VERSIONED_RECORDS);
+    }
+}
+
+/// Whitelist and settings records decode through their legacy encoding and
+/// are written back in the principal form. The subscriber caches are cleared
+/// instead and refilled by the next `cache_subscribers_fetch`.
+fn canister_ids_migrate() {
+    let whitelist: Vec<_> = WHITELIST.with(|p| p.borrow().iter().collect());
+
+    for (topic, canister_ids) in whitelist {
+        WHITELIST.with(|p| p.borrow_mut().insert(topic, canister_ids));
+    }
+
+    let settings: Vec<_> = CANISTER_SETTINGS.with(|p| p.borrow().iter().collect());
+
+    for (canister_name, settings) in settings {
+        CANISTER_SETTINGS.with(|p| {
+            let mut map = p.borrow_mut();
+
+            if settings.canister_id == Principal::anonymous() {
+                ic_cdk::print(format!("Removing settings for {} with an invalid canister id", canister_name));
+                map.remove(&canister_name);
+            } else {
+                map.insert(canister_name, settings);
+            }
+        });
+    }
+
+    cache_subscribers_clear();
+}
+
+/// Older records are upgraded when they are read; rewriting them means later
+/// builds only need to read the versions they were released with. The
+/// subscriber caches are refilled from the registry instead.
+fn records_rewrite() {
+    let whitelist: Vec<_> = WHITELIST.with(|p| p.borrow().iter().collect());
+
+    for (topic, canister_ids) in whitelist {
+        WHITELIST.with(|p| p.borrow_mut().insert(topic, canister_ids));
+    }
+
+    let settings: Vec<_> = CANISTER_SETTINGS.with(|p| p.borrow().iter().collect());
+
+    for (canister_name, settings) in settings {
+        CANISTER_SETTINGS.with(|p| p.borrow_mut().insert(canister_name, settings));
+    }
+
+    cache_subscribers_clear();
 }
 
 
@@ -116,65 +486,164 @@ thread_local! {
 //  fifo_consumer       Processes messages in chunks
 //  fifo_buffer_size    Get the current queue size
 //  fifo_buffer_empty   Clear the queue
+//  queue_depth         Queued messages per topic and publisher
 //  serialize_message   Serialize message before storing in queue
 //  deserialize_message Deserialize messages stored in the queue
 //
+//  The queue is limited in total, per topic and per
+//  publisher by the queue config.
+//
 /******************************************************/
 
-#[ic_cdk_macros::update]
+/// Queues without a publisher, so without the whitelist, the publisher
+/// quota and the rate limits. Publishers go through `intake`.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
 fn fifo_producer(msg: Message) -> Result<String, String> {
-    if msg.topic.len() == 0 || msg.value.len() == 0 {
-        return Err("Message is invalid. Message is missing topic and/or value".to_string())
+    queue_push(msg, None).map_err(|err| err.to_string())?;
+    Ok("Success: Element was inserted in the queue".to_string())
+}
+
+/// Adds a message to the back of the queue if none of the depth limits is
+/// reached and returns its ID.
+fn queue_push(msg: Message, publisher: Option<Principal>) -> Result<String, IntakeError> {
+    queue_check(std::slice::from_ref(&msg), publisher).map_err(|(_, err)| err)?;
+    Ok(queue_insert(msg, publisher))
+}
+
+/// Checks that all of the messages fit in the queue together. The error
+/// carries the index of the first message that does not.
+fn queue_check(msgs: &[Message], publisher: Option<Principal>) -> Result<(), (usize, IntakeError)> {
+    if let Some(index) = msgs.iter().position(|msg| msg.topic.is_empty() || msg.value.is_empty()) {
+        return Err((index, IntakeError::Invalid("Message is invalid. Message is missing topic and/or value".to_string())));
     }
-    
-    let do_insert = || -> Result<String, String> {
-        let msg_str: String = serialize_message(msg);
-        unsafe {
-            let mut fifo_ref = LOCKCD.write().unwrap();
-            fifo_ref.push_back(msg_str);
-            drop(fifo_ref);
+
+    let config = queue_config_get();
+    let depth = fifo_buffer_size() as u64;
+    let publisher_depth = publisher.map_or(0, |publisher| {
+        DEPTH_BY_PUBLISHER.with(|p| p.borrow().get(&publisher).copied().unwrap_or(0))
+    });
+
+    let mut topic_added: HashMap<&str, u64> = HashMap::new();
+
+    for (index, msg) in msgs.iter().enumerate() {
+        let added = index as u64 + 1;
+        let topic_depth = DEPTH_BY_TOPIC.with(|p| p.borrow().get(&msg.topic).copied().unwrap_or(0));
+        let topic_added = topic_added.entry(msg.topic.as_str()).or_insert(0);
+        *topic_added += 1;
+
+        if depth + added > config.max_depth {
+            return Err((index, IntakeError::QueueFull {
+                reason: "The queue is full".to_string(),
+                retry_after_secs: queue_retry_after(&config, depth + added - config.max_depth),
+            }));
+        }
+
+        if topic_depth + *topic_added > config.max_topic_depth {
+            return Err((index, IntakeError::QueueFull {
+                reason: format!("The queue for topic {} is full", msg.topic),
+                retry_after_secs: queue_retry_after(&config, topic_depth + *topic_added - config.max_topic_depth),
+            }));
         }
-        Ok("Success: Element was inserted in the queue1".to_string())
+
+        if publisher.is_some() && publisher_depth + added > config.max_publisher_depth {
+            return Err((index, IntakeError::RateLimited {
+                reason: "The publisher has too many messages in the queue".to_string(),
+                retry_after_secs: queue_retry_after(&config, publisher_depth + added - config.max_publisher_depth),
+            }));
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds a message that passed `queue_check` and returns its ID.
+fn queue_insert(msg: Message, publisher: Option<Principal>) -> String {
+    DEPTH_BY_TOPIC.with(|p| *p.borrow_mut().entry(msg.topic.clone()).or_insert(0) += 1);
+
+    if let Some(publisher) = publisher {
+        DEPTH_BY_PUBLISHER.with(|p| *p.borrow_mut().entry(publisher).or_insert(0) += 1);
+    }
+
+    let id = create_uuid();
+    let queued = QueuedMessage {
+        id: id.clone(),
+        topic: msg.topic,
+        value: msg.value,
+        publisher,
+        queued_at: ic_cdk::api::time(),
     };
+    let queued = serde_json::to_string(&queued).expect("Could not serialize the queued message");
 
-    if let Err(_err) = do_insert() {
-        return Err("Failed to perform necessary steps".to_string())
-    } else {
-        return Ok("Success: Element was inserted in the queue2".to_string())
+    unsafe { LOCKCD.write().unwrap().push_back(queued); }
+    id
+}
+
+fn queue_front() -> Option<QueuedMessage> {
+    let front = unsafe { LOCKCD.write().unwrap().front().cloned() };
+    front.map(|queued| serde_json::from_str(&queued).expect("Could not deserialize the queued message"))
+}
+
+fn queue_pop() {
+    let front = unsafe { LOCKCD.write().unwrap().pop_front() };
+    let queued: QueuedMessage = match front {
+        Some(queued) => serde_json::from_str(&queued).expect("Could not deserialize the queued message"),
+        None => return,
+    };
+
+    DEPTH_BY_TOPIC.with(|p| depth_release(&mut p.borrow_mut(), queued.topic));
+
+    if let Some(publisher) = queued.publisher {
+        DEPTH_BY_PUBLISHER.with(|p| depth_release(&mut p.borrow_mut(), publisher));
     }
 }
 
+fn depth_release<K: std::hash::Hash + Eq>(depths: &mut HashMap<K, u64>, key: K) {
+    if let Some(depth) = depths.get_mut(&key) {
+        *depth = depth.saturating_sub(1);
+
+        if *depth == 0 {
+            depths.remove(&key);
+        }
+    }
+}
+
+/// Estimates how long the consumer needs to take `excess` messages off the
+/// queue, at one chunk per tick.
+fn queue_retry_after(config: &QueueConfig, excess: u64) -> u64 {
+    excess.div_ceil(config.chunk_size).max(1) * config.interval_secs
+}
+
+/// Takes messages off the front of the queue only while there is capacity
+/// for all of their deliveries. The first message that does not fit stops
+/// the tick, so the rest stay queued in order.
 fn fifo_consumer() {    
-    let do_insert = || -> Result<String, String> {
-        unsafe {
-            let mut fifo_ref = LOCKCD.write().unwrap();
-            let mut chunk_size: usize = MAX_CHUNK_SIZE;
+    track_cycles_used();
 
-            if fifo_ref.len() < MAX_CHUNK_SIZE {
-                chunk_size = fifo_ref.len();
-            }
+    let config = queue_config_get();
+    cache_refresh_if_stale(&config);
+    circuit_tick(&config);
+    batch_tick(&config);
 
-            if chunk_size <= 0 {
-                return Ok("Success: There are no messages to process".to_string())
-            }
+    let mut started: u64 = 0;
 
-            let drained = fifo_ref.drain(0..chunk_size).collect::<VecDeque<String>>();
-            
-            for msg in drained.iter() {
-                ic_cdk::spawn( 
-                    route_message(deserialize_message(msg.clone()))
-                );
-            }
+    while started < config.chunk_size {
+        let envelope = match queue_front() {
+            Some(queued) => Envelope::from(queued),
+            None => break,
+        };
 
-            drop(fifo_ref);
+        if !delivery_dispatch(&config, &envelope) {
+            break;
         }
-        Ok("Success: Messages were processed successfully".to_string())
-    };
 
-    if let Ok(_res) = do_insert() {
-        ic_cdk::print(_res.to_string());
+        queue_pop();
+        started += 1;
+    }
+
+    if started == 0 {
+        ic_cdk::print(format!("Success: No messages were started, {} deliveries are in flight", in_flight_total()));
     } else {
-        ic_cdk::print("Success: Messages were processed successfully".to_string());
+        ic_cdk::print(format!("Success: {} messages were started", started));
     }
 }
 
@@ -192,6 +661,22 @@ fn fifo_buffer_empty() -> () {
         let mut deque = LOCKCD.write().unwrap();
         let _ = deque.drain(..).collect::<VecDeque<String>>();
     }
+
+    DEPTH_BY_TOPIC.with(|p| p.borrow_mut().clear());
+    DEPTH_BY_PUBLISHER.with(|p| p.borrow_mut().clear());
+}
+
+#[ic_cdk_macros::query]
+fn queue_depth() -> QueueDepth {
+    let topics = DEPTH_BY_TOPIC.with(|p| {
+        p.borrow().iter().map(|(topic, depth)| TopicDepth { topic: topic.clone(), depth: *depth }).collect()
+    });
+
+    let publishers = DEPTH_BY_PUBLISHER.with(|p| {
+        p.borrow().iter().map(|(publisher, depth)| PublisherDepth { publisher: *publisher, depth: *depth }).collect()
+    });
+
+    QueueDepth { total: fifo_buffer_size() as u64, topics, publishers }
 }
 
 #[ic_cdk_macros::query]
@@ -220,11 +705,11 @@ fn deserialize_message(msg: String) -> Message {
 /******************************************************/
 
 #[ic_cdk_macros::update]
-fn whitelist_register(topic: String, canister_id: String) -> () {
+fn whitelist_register(topic: String, canister_id: Principal) -> () {
     let cid = WHITELIST.with(|p| p.borrow().get(&topic));
     
     if cid.is_none() {
-        let mut ids = Vec::<String>::new();
+        let mut ids = Vec::<Principal>::new();
         ids.push(canister_id);
 
         let canister_ids = CanisterIds {
@@ -234,7 +719,7 @@ fn whitelist_register(topic: String, canister_id: String) -> () {
         WHITELIST.with(|p| p.borrow_mut().insert(topic, canister_ids));
     } else {
         let mut ids =  cid.unwrap().ids;
-        ids.push(canister_id.to_string());
+        ids.push(canister_id);
 
         let canister_ids = CanisterIds {
             ids: ids.clone()
@@ -244,11 +729,11 @@ fn whitelist_register(topic: String, canister_id: String) -> () {
 }
 
 #[ic_cdk_macros::update]
-fn whitelist_unregister(topic: String, canisterId: String) -> Result<String, String> {
+fn whitelist_unregister(topic: String, canisterId: Principal) -> Result<String, String> {
     let cid = WHITELIST.with(|p| p.borrow().get(&topic.clone()));
     
     if cid.is_some() {
-        let mut ids: Vec<String> = cid.unwrap().ids;
+        let mut ids: Vec<Principal> = cid.unwrap().ids;
         ids.retain(|x| *x != canisterId);
         
         WHITELIST.with(|p| p.borrow_mut().insert(topic.clone(), CanisterIds { ids: ids.clone() }));
@@ -259,7 +744,7 @@ fn whitelist_unregister(topic: String, canisterId: String) -> Result<String, Str
 }
 
 #[ic_cdk_macros::query]
-fn whitelist_lookup(topic: String) -> Vec<String> {
+fn whitelist_lookup(topic: String) -> Vec<Principal> {
     let cid = WHITELIST.with(|p| p.borrow().get(&topic));
     
     if cid.is_some() {
@@ -270,11 +755,11 @@ fn whitelist_lookup(topic: String) -> Vec<String> {
 }
 
 #[ic_cdk_macros::query]
-fn whitelist_canister_check(topic: String, canisterId: String) -> Result<String, String> {
+fn whitelist_canister_check(topic: String, canisterId: Principal) -> Result<String, String> {
     let cid = WHITELIST.with(|p| p.borrow().get(&topic));
     
     if cid.is_some() {
-        let ids: Vec<String> = cid.unwrap().ids;
+        let ids: Vec<Principal> = cid.unwrap().ids;
 
         if ids.iter().any(|i| i == &canisterId) {
             Ok("Success: The canister id is whitelisted for this topic".to_string())  
@@ -292,34 +777,105 @@ fn whitelist_canister_check(topic: String, canisterId: String) -> Result<String,
 //
 //  INTAKE
 //
-//  intake      The main message intake function, it checks
-//              if the canister sending the request is
-//              whitelisted 
+//  intake          The main message intake function, it checks
+//                  if the canister sending the request is
+//                  whitelisted 
+//  intake_batch    Intake for many messages in one call,
+//                  returns the IDs they were queued under
+//
+//  A full queue is reported as QueueFull, or as RateLimited
+//  when the publisher has used up its share or its rate
+//  limit for the topic, with a hint on when to retry.
 //
 /******************************************************/
 
 #[ic_cdk_macros::update]
-pub async fn intake(msg: Message) -> Result<String, String> {
-    let subscriber_principal_id = ic_cdk::caller();
-    let Message { ref topic, ref value } = msg;
-    let whitelist_check = whitelist_canister_check(topic.clone(), subscriber_principal_id.to_string());
-
-    if whitelist_check.is_ok() {
-        let inject = fifo_producer(Message {
-            topic: topic.clone(),
-            value: value.clone(),
+pub async fn intake(msg: Message) -> Result<String, IntakeError> {
+    let publisher = ic_cdk::caller();
+
+    if whitelist_canister_check(msg.topic.clone(), publisher).is_err() {
+        return Err(IntakeError::NotWhitelisted);
+    }
+
+    rate_limit_take(&publisher, &msg.topic, 1)?;
+    queue_push(msg, Some(publisher))?;
+    Ok("Success: The message has been pushed to the queue".to_string())
+}
+
+/// The whitelist is checked once per topic. In `AllOrNothing` mode the first
+/// message that cannot be queued rejects the batch and nothing is queued; in
+/// `PerItem` mode every message gets its own result, in the order sent.
+#[ic_cdk_macros::update]
+pub async fn intake_batch(msgs: Vec<Message>, mode: IntakeBatchMode) -> Result<Vec<Result<String, IntakeError>>, IntakeBatchError> {
+    let publisher = ic_cdk::caller();
+
+    if msgs.is_empty() || msgs.len() > MAX_INTAKE_BATCH {
+        return Err(IntakeBatchError {
+            index: None,
+            error: IntakeError::Invalid(format!("A batch must have between 1 and {} messages", MAX_INTAKE_BATCH)),
         });
+    }
 
-        let inject = fifo_producer(msg);
+    let mut whitelisted: HashMap<String, bool> = HashMap::new();
 
-        if inject.is_ok() {
-            Ok("Success: The message has been pushed to the queue".to_string())
-        } else {
-            Err("Fail: The message was rejected by the queue".to_string())
+    for msg in msgs.iter() {
+        whitelisted.entry(msg.topic.clone())
+            .or_insert_with(|| whitelist_canister_check(msg.topic.clone(), publisher).is_ok());
+    }
+
+    match mode {
+        IntakeBatchMode::AllOrNothing => intake_batch_all(msgs, publisher, &whitelisted),
+        IntakeBatchMode::PerItem => Ok(intake_batch_each(msgs, publisher, &whitelisted)),
+    }
+}
+
+/// Rate limits are taken per topic for all of its messages at once, and
+/// handed back if a later topic is limited.
+fn intake_batch_all(msgs: Vec<Message>, publisher: Principal, whitelisted: &HashMap<String, bool>) -> Result<Vec<Result<String, IntakeError>>, IntakeBatchError> {
+    if let Some(index) = msgs.iter().position(|msg| !whitelisted[&msg.topic]) {
+        return Err(IntakeBatchError { index: Some(index as u64), error: IntakeError::NotWhitelisted });
+    }
+
+    queue_check(&msgs, Some(publisher))
+        .map_err(|(index, error)| IntakeBatchError { index: Some(index as u64), error })?;
+
+    // Topics in order of their first message, so an error points at it
+    let mut topics: Vec<(&str, usize, u64)> = Vec::new();
+
+    for (index, msg) in msgs.iter().enumerate() {
+        match topics.iter_mut().find(|(topic, _, _)| *topic == msg.topic) {
+            Some((_, _, count)) => *count += 1,
+            None => topics.push((msg.topic.as_str(), index, 1)),
         }
-    } else {
-        Err("Fail: The sending canister is not whitelisted".to_string())
     }
+
+    for (taken, (topic, index, count)) in topics.iter().enumerate() {
+        if let Err(error) = rate_limit_take(&publisher, topic, *count) {
+            for (topic, _, count) in topics[..taken].iter() {
+                rate_limit_refund(&publisher, topic, *count);
+            }
+
+            return Err(IntakeBatchError { index: Some(*index as u64), error });
+        }
+    }
+
+    Ok(msgs.into_iter().map(|msg| Ok(queue_insert(msg, Some(publisher)))).collect())
+}
+
+fn intake_batch_each(msgs: Vec<Message>, publisher: Principal, whitelisted: &HashMap<String, bool>) -> Vec<Result<String, IntakeError>> {
+    msgs.into_iter()
+        .map(|msg| {
+            if !whitelisted[&msg.topic] {
+                return Err(IntakeError::NotWhitelisted);
+            }
+
+            // Checked first, so an invalid or unqueueable message does not
+            // use up a token
+            queue_check(std::slice::from_ref(&msg), Some(publisher)).map_err(|(_, err)| err)?;
+            rate_limit_take(&publisher, &msg.topic, 1)?;
+            Ok(queue_insert(msg, Some(publisher)))
+        })
+        .collect()
 }
     
     
@@ -330,7 +886,10 @@ pub async fn intake(msg: Message) -> Result<String, String> {
 //  start_with_interval_secs    Starts a timer, which works as
 //                              a periodic check to see if there
 //                              are messages to process
-//  
+//  stop                        Stops the timer
+//
+//  There is at most one consumer timer; starting it again
+//  replaces the running one.
 //
 /******************************************************/
 
@@ -346,20 +905,43 @@ pub fn counter() -> u32 {
     COUNTER.with(|counter| *counter.borrow())
 }
 
-#[ic_cdk_macros::update]
-pub fn start_with_interval_secs(secs: u64) {
-    let secs = Duration::from_secs(secs);
-    let timer_id = ic_cdk_timers::set_timer_interval(secs, fifo_consumer);
-    TIMER_IDS.with(|timer_ids| timer_ids.borrow_mut().push(timer_id));
+/// Stores the interval, so it is also used after an upgrade.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+pub fn start_with_interval_secs(secs: u64) -> Result<String, String> {
+    queue_config_set(QueueConfigUpdate {
+        interval_secs: Some(secs),
+        ..Default::default()
+    })?;
+
+    // A running timer was already moved to the new interval
+    if !consumer_timer_running() {
+        consumer_timer_start(secs);
+    }
+
+    Ok(format!("Success: The consumer runs every {} seconds", secs))
 }
 
-#[ic_cdk_macros::update]
+/// The timer is started again by the next upgrade.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
 pub fn stop() {
-    TIMER_IDS.with(|timer_ids| {
-        if let Some(timer_id) = timer_ids.borrow_mut().pop() {
-            ic_cdk_timers::clear_timer(timer_id);
-        }
-    });
+    consumer_timer_stop();
+}
+
+fn consumer_timer_start(interval_secs: u64) {
+    consumer_timer_stop();
+
+    let timer_id = ic_cdk_timers::set_timer_interval(Duration::from_secs(interval_secs), fifo_consumer);
+    CONSUMER_TIMER.with(|p| *p.borrow_mut() = Some(timer_id));
+}
+
+fn consumer_timer_running() -> bool {
+    CONSUMER_TIMER.with(|p| p.borrow().is_some())
+}
+
+fn consumer_timer_stop() {
+    if let Some(timer_id) = CONSUMER_TIMER.with(|p| p.borrow_mut().take()) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
 }
 
 #[ic_cdk_macros::query]
@@ -372,22 +954,164 @@ pub fn cycles_used() -> u64 {
 //
 //  MESSAGE ROUTER
 //
+//  route_message           Deliver a message, or queue it when
+//                          there is no capacity
+//  in_flight_status        Deliveries waiting for a reply
+//
+//  Deliveries count as in flight from the moment they are
+//  reserved until the subscriber replies, globally and per
+//  subscriber, and both counts are capped by the queue config.
+//  Subscribers with an open circuit do not count, their copy
+//  of the message is buffered by the circuit breaker, and
+//  neither do subscribers that receive batches until their
+//  batch is sent.
+//
 /******************************************************/
 
-#[ic_cdk_macros::update]
+/// Skips the whitelist and rate limits like `fifo_producer`, so it is
+/// limited to controllers.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
 async fn route_message(message: Message) -> () {
-    for subscriber in cache_subscribers(message.clone().topic).await.iter() {
-        let subscriber_data = cache_subscriber_data(subscriber.to_string()).await.unwrap();
-        
-        ic_cdk::spawn( 
-            route_message_execute(subscriber_data.canister_id.to_string(), subscriber_data.callback.to_string(), message.clone().value)
-        );
+    let envelope = Envelope {
+        id: create_uuid(),
+        topic: message.topic.clone(),
+        value: message.value.clone(),
+        publisher: None,
+        queued_at: ic_cdk::api::time(),
+    };
+
+    if !delivery_dispatch(&queue_config_get(), &envelope) {
+        if let Err(err) = fifo_producer(message) {
+            ic_cdk::print(err);
+        }
     }
 }
 
 #[ic_cdk_macros::query]
-async fn route_message_execute(canister_id: String, callback: String, val: String) -> () {
-    let transfer_result: (Result<String, String>, ) = ic_cdk::call(Principal::from_text(&canister_id.to_string()).expect("Could not decode the principal."), &callback.to_string(), (&val.to_string(),)).await.unwrap();
+fn in_flight_status() -> InFlightStatus {
+    let subscribers = IN_FLIGHT_BY_SUBSCRIBER.with(|p| {
+        p.borrow()
+            .iter()
+            .map(|(id, count)| SubscriberInFlight { subscriber_id: id.clone(), in_flight: *count })
+            .collect()
+    });
+
+    InFlightStatus { total: in_flight_total(), subscribers }
+}
+
+fn in_flight_total() -> u64 {
+    IN_FLIGHT.with(|p| *p.borrow())
+}
+
+/// Active subscribers of a topic, as currently cached.
+fn delivery_targets(topic: &str) -> Vec<SubscriberCache> {
+    let ids = SUBSCRIBER_CACHE.with(|p| p.borrow().get(&topic.to_string())).map_or(Vec::new(), |c| c.ids);
+
+    SUBSCRIBER_DATA_CACHE.with(|p| {
+        let cache = p.borrow();
+        ids.iter().filter_map(|id| cache.get(id)).collect()
+    })
+}
+
+/// Starts the deliveries of a message if there is capacity for them.
+fn delivery_dispatch(config: &QueueConfig, envelope: &Envelope) -> bool {
+    let (targets, held): (Vec<SubscriberCache>, Vec<SubscriberCache>) = delivery_targets(&envelope.topic)
+        .into_iter()
+        .partition(|t| circuit_admits(&t.id));
+
+    let (batched, targets): (Vec<SubscriberCache>, Vec<SubscriberCache>) = targets
+        .into_iter()
+        .partition(|t| batch_settings(&t.id).is_some());
+
+    if batched.iter().any(|t| batch_full(&t.id)) || !delivery_reserve(config, &targets) {
+        return false;
+    }
+
+    circuit_hold(&held, envelope);
+    batch_add(&batched, envelope);
+    delivery_start(envelope, targets);
+    true
+}
+
+/// Reserves one delivery per target if all of them fit. A message with more
+/// subscribers than the global limit is let through once nothing else is in
+/// flight, so it cannot block the queue for good.
+fn delivery_reserve(config: &QueueConfig, targets: &[SubscriberCache]) -> bool {
+    let in_flight = in_flight_total();
+    let needed = targets.len() as u64;
+
+    if in_flight > 0 && in_flight + needed > config.max_concurrency {
+        return false;
+    }
+
+    let subscriber_full = IN_FLIGHT_BY_SUBSCRIBER.with(|p| {
+        let map = p.borrow();
+        targets.iter().any(|t| map.get(&t.id).is_some_and(|count| *count >= config.max_per_subscriber))
+    });
+
+    if subscriber_full {
+        return false;
+    }
+
+    IN_FLIGHT.with(|p| *p.borrow_mut() += needed);
+    IN_FLIGHT_BY_SUBSCRIBER.with(|p| {
+        let mut map = p.borrow_mut();
+
+        for target in targets.iter() {
+            *map.entry(target.id.clone()).or_insert(0) += 1;
+        }
+    });
+
+    true
+}
+
+fn delivery_release(subscriber_id: &str) {
+    IN_FLIGHT.with(|p| {
+        let mut count = p.borrow_mut();
+        *count = count.saturating_sub(1);
+    });
+
+    IN_FLIGHT_BY_SUBSCRIBER.with(|p| {
+        let mut map = p.borrow_mut();
+
+        if let Some(count) = map.get_mut(subscriber_id) {
+            *count = count.saturating_sub(1);
+
+            if *count == 0 {
+                map.remove(subscriber_id);
+            }
+        }
+    });
+}
+
+/// Starts the deliveries reserved by `delivery_reserve`.
+fn delivery_start(envelope: &Envelope, targets: Vec<SubscriberCache>) {
+    for target in targets.into_iter() {
+        circuit_started(&target.id);
+        ic_cdk::spawn(delivery_execute(target.id, target.canister_id, target.callback, envelope.clone()));
+    }
+}
+
+async fn delivery_execute(subscriber_id: String, canister_id: Principal, callback: String, envelope: Envelope) {
+    let (delivered, cycles) = subscriber_call(canister_id, &callback, envelope.value.clone()).await;
+
+    cycles_record(&subscriber_id, std::slice::from_ref(&envelope), cycles);
+    delivery_release(&subscriber_id);
+    circuit_result(&queue_config_get(), &subscriber_id, delivered);
+}
+
+/// Returns whether the subscriber was reached and what the call cost. Failed
+/// calls are only logged, a trap after the call would leave the in flight
+/// counts too high.
+async fn subscriber_call(canister_id: Principal, callback: &str, val: String) -> (bool, u128) {
+    let (result, cycles): (CallResult<(Result<String, String>, )>, u128) =
+        cycles_call(canister_id, callback, (&val,)).await;
+
+    if let Err((code, message)) = &result {
+        ic_cdk::print(format!("Delivery to {} failed: {:?}: {}", canister_id, code, message));
+    }
+
+    (result.is_ok(), cycles)
 }
 
 
@@ -426,20 +1150,25 @@ async fn cache_subscriber_data(topic_id: String) -> Option<SubscriberCache> {
 }
 
 #[ic_cdk_macros::update]
-async fn cache_subscribers_fetch() -> () {
-    let registry_canister = canister_settings_get("registry_backend".to_string()).unwrap().canister_id;
-    let topics: (Vec<Topics>, ) = ic_cdk::call(Principal::from_text(registry_canister.clone()).expect("Could not decode the principal."), "topics", ()).await.unwrap();
-    let subscribers: (Vec<Subscribers>, ) = ic_cdk::call(Principal::from_text(registry_canister.clone()).expect("Could not decode the principal."), "subscribers", ()).await.unwrap();
-    let mut ids: Vec<String> = Vec::new();
+async fn cache_subscribers_fetch() -> Result<String, String> {
+    let registry = registry_canister()?;
 
-    for i in subscribers.0.iter() {
+    let topics: Vec<Topics> = registry_fetch_all(registry, "topics").await?;
+    let subscribers: Vec<Subscribers> = registry_fetch_all(registry, "subscribers").await?;
+
+    // Replace the cache, so removed subscribers stop receiving messages
+    cache_subscribers_clear();
+    CACHE_FETCHED_AT.with(|p| *p.borrow_mut() = ic_cdk::api::time());
+
+    for i in subscribers.iter() {
         let topic_id = &i.topic;
 
-        let index: usize = topics.0
-            .iter()
-            .position(|x| x.id == topic_id.to_string()).unwrap();
+        let index: usize = match topics.iter().position(|x| x.id == *topic_id) {
+            Some(index) => index,
+            None => continue,
+        };
 
-        let topic_name = topics.0.get(index).unwrap().name.to_string();
+        let topic_name = topics.get(index).unwrap().name.to_string();
         let mut topic_cache = SUBSCRIBER_CACHE.with(|p| p.borrow().get(&topic_name));
         let ts = ic_cdk::api::time();
 
@@ -451,7 +1180,7 @@ async fn cache_subscribers_fetch() -> () {
 
         let subscriber_insert = SubscriberCache {
             id: i.id.to_string(),
-            canister_id: i.canister_id.to_string(),
+            canister_id: i.canister_id,
             callback: i.callback.to_string(),
             name: i.name.to_string(),
             description: i.description.to_string(),
@@ -477,6 +1206,50 @@ async fn cache_subscribers_fetch() -> () {
             SUBSCRIBER_DATA_CACHE.with(|p| p.borrow_mut().insert(i.id.to_string(), subscriber_insert));
         }
     }
+
+    Ok(format!("Success: Cached {} subscribers", subscribers.len()))
+}
+
+/// Pages through one of the registry's list endpoints until the cursor runs out.
+async fn registry_fetch_all<T>(registry: Principal, method: &str) -> Result<Vec<T>, String>
+where
+    T: CandidType + for<'de> Deserialize<'de>,
+{
+    let mut items: Vec<T> = Vec::new();
+    let mut args = ListArgs::default();
+
+    loop {
+        let page: (Page<T>, ) = ic_cdk::call(registry, method, (Some(args.clone()), )).await
+            .map_err(|(code, message)| format!("Could not call {} on the registry: {:?}: {}", method, code, message))?;
+        items.extend(page.0.items);
+
+        match page.0.next {
+            Some(next) => args.start_after = Some(next),
+            None => break,
+        }
+    }
+
+    Ok(items)
+}
+
+/// Refreshes the cache in the background once it is older than the
+/// configured TTL.
+fn cache_refresh_if_stale(config: &QueueConfig) {
+    let now = ic_cdk::api::time();
+    let fetched_at = CACHE_FETCHED_AT.with(|p| *p.borrow());
+
+    if now.saturating_sub(fetched_at) < config.cache_ttl_secs.saturating_mul(1_000_000_000) {
+        return;
+    }
+
+    // Set before the fetch so the ticks until it returns do not start another
+    CACHE_FETCHED_AT.with(|p| *p.borrow_mut() = now);
+
+    ic_cdk::spawn(async {
+        if let Err(err) = cache_subscribers_fetch().await {
+            ic_cdk::print(format!("Could not refresh the subscriber cache: {}", err));
+        }
+    });
 }
 
 #[ic_cdk_macros::update]
@@ -490,14 +1263,19 @@ fn cache_subscribers_clear() -> () {
 //
 //  CANISTER SETTINGS
 //
-//  canister_settings_store Canister settings are pushed to this function
+//  canister_settings_store Set a canister ID by hand, for controllers
 //  canister_settings_get   Get the canister ID of a canister
+//  config_apply            The registry pushes its config document here
+//  config_sync             Pull the config document from the registry
+//  config_version          Version of the applied config document
 //
 /******************************************************/
 
-#[ic_cdk_macros::update]
-pub async fn canister_settings_store(canister_name: String, canister_id: String) -> () {
-    let _ = CANISTER_SETTINGS.with(|p| p.borrow_mut().insert(canister_name.to_string(), CanisterSettings { canister_id: canister_id.to_string() }));
+/// The registry canister is looked up here when the init args do not name
+/// it, so only controllers can change the settings directly.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+pub async fn canister_settings_store(canister_name: String, canister_id: Principal) -> () {
+    let _ = CANISTER_SETTINGS.with(|p| p.borrow_mut().insert(canister_name.to_string(), CanisterSettings { canister_id }));
 }
 
 #[ic_cdk_macros::query]
@@ -505,4 +1283,79 @@ pub fn canister_settings_get(canister_name: String) -> Option<CanisterSettings>
     CANISTER_SETTINGS.with(|p| p.borrow().get(&canister_name))
 }
 
+#[ic_cdk_macros::update]
+pub fn config_apply(document: ConfigDocument) -> Result<u64, String> {
+    config_source_check()?;
+    Ok(config_store(document))
+}
+
+#[ic_cdk_macros::update]
+pub async fn config_sync() -> Result<u64, String> {
+    let registry = registry_canister()?;
+
+    let result: CallResult<(Result<ConfigDocument, String>, )> =
+        ic_cdk::call(registry, "config_pull", ()).await;
+
+    match result {
+        Ok((Ok(document), )) => Ok(config_store(document)),
+        Ok((Err(err), )) => Err(err),
+        Err((code, message)) => Err(format!("{:?}: {}", code, message)),
+    }
+}
+
+#[ic_cdk_macros::query]
+pub fn config_version() -> u64 {
+    CONFIG_VERSION.with(|p| *p.borrow().get())
+}
+
+/// Pulls the config once the canister is running, since calls cannot be
+/// made from `init` or `post_upgrade`.
+fn config_sync_schedule() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            if let Err(err) = config_sync().await {
+                ic_cdk::print(format!("Could not pull the config: {}", err));
+            }
+        });
+    });
+}
+
+/// Documents older than the applied one are ignored, so a delayed push cannot
+/// undo a newer pull. Entries are only added or replaced; settings stored
+/// through `canister_settings_store` stay.
+fn config_store(document: ConfigDocument) -> u64 {
+    let applied = config_version();
+
+    if document.version <= applied {
+        return applied;
+    }
+
+    CANISTER_SETTINGS.with(|p| {
+        let mut map = p.borrow_mut();
+
+        for entry in document.canisters {
+            map.insert(entry.canister_name, CanisterSettings { canister_id: entry.canister_id });
+        }
+    });
+
+    CONFIG_VERSION.with(|p| p.borrow_mut().set(document.version)).expect("Could not store the config version");
+    document.version
+}
+
+/// Only the registry and the controllers can change the settings, and only
+/// the controllers while the registry is not known.
+fn config_source_check() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+
+    if ic_cdk::api::is_controller(&caller) {
+        return Ok(());
+    }
+
+    match registry_canister() {
+        Ok(registry) if registry == caller => Ok(()),
+        Ok(_) => Err("Only the registry can push the config".to_string()),
+        Err(_) => Err("Only a controller can push the config until the registry is configured".to_string()),
+    }
+}
+
 
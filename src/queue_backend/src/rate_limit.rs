@@ -0,0 +1,372 @@
+use candid::Principal;
+use ic_cdk::api::call::CallResult;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use crate::types::{IntakeError, RateLimit, RateLimitUsage};
+use crate::{caller_is_controller, canister_settings_get, queue_settings, RATE_LIMITS};
+
+/// `log_type` of warnings in logging_backend.
+const LOG_TYPE_WARNING: u8 = 2;
+const LOG_ORIGIN: &str = "queue_backend";
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Keeps bucket levels well inside `u128`.
+const MAX_CAPACITY: u64 = 1_000_000;
+const MAX_PERIOD_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// One token is `period_secs` in nanoseconds worth of level, and the level
+/// grows by `capacity` per nanosecond, so refilling never rounds.
+struct Bucket {
+    level: u128,
+    updated_at: u64,
+    allowed: u64,
+    rejected: u64,
+    rejected_unlogged: u64,
+    logged_at: Option<u64>,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit, now: u64) -> Self {
+        Bucket {
+            level: bucket_full(limit),
+            updated_at: now,
+            allowed: 0,
+            rejected: 0,
+            rejected_unlogged: 0,
+            logged_at: None,
+        }
+    }
+
+    /// Refills the bucket up to `now` and takes `count` tokens if they are
+    /// all there. Otherwise returns the seconds until they are.
+    fn take(&mut self, limit: &RateLimit, count: u64, now: u64) -> Result<(), u64> {
+        self.level = bucket_level(limit, self, now);
+        self.updated_at = now;
+
+        let units = token_units(limit) * count as u128;
+
+        if self.level >= units {
+            self.level -= units;
+            self.allowed += count;
+            return Ok(());
+        }
+
+        self.rejected += count;
+        self.rejected_unlogged += count;
+
+        // Refill runs at `capacity` units per nanosecond
+        let wait_ns = (units - self.level).div_ceil(limit.capacity as u128);
+        Err(wait_ns.div_ceil(NANOS_PER_SEC).max(1) as u64)
+    }
+
+    fn refund(&mut self, limit: &RateLimit, count: u64) {
+        self.level = (self.level + token_units(limit) * count as u128).min(bucket_full(limit));
+        self.allowed = self.allowed.saturating_sub(count);
+    }
+
+    fn tokens(&self, limit: &RateLimit, now: u64) -> u64 {
+        (bucket_level(limit, self, now) / token_units(limit)) as u64
+    }
+}
+
+thread_local! {
+    static BUCKETS: RefCell<HashMap<String, Bucket>> = RefCell::new(HashMap::new());
+}
+
+
+/******************************************************/
+//
+//  RATE LIMITS
+//
+//  Token buckets per publisher and topic, checked by intake.
+//  A bucket holds up to `capacity` messages and refills at
+//  `capacity` messages per `period_secs`. Publishers without
+//  a limit for a topic are only bound by the queue depth.
+//
+//  Bucket levels live on the heap and start full after an
+//  upgrade; the limits themselves are stable.
+//
+//  rate_limit_set      Add or change a limit
+//  rate_limit_remove   Remove a limit
+//  rate_limit_list     All configured limits
+//  rate_limit_usage    Bucket levels and counts of the caller,
+//                      or of every publisher for controllers
+//
+/******************************************************/
+
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn rate_limit_set(limit: RateLimit) -> Result<String, String> {
+    if limit.publisher == Principal::anonymous() {
+        return Err("publisher cannot be the anonymous principal".to_string());
+    }
+
+    if limit.topic.is_empty() {
+        return Err("topic cannot be empty".to_string());
+    }
+
+    if limit.capacity == 0 || limit.capacity > MAX_CAPACITY {
+        return Err(format!("capacity must be between 1 and {}", MAX_CAPACITY));
+    }
+
+    if limit.period_secs == 0 || limit.period_secs > MAX_PERIOD_SECS {
+        return Err(format!("period_secs must be between 1 and {}", MAX_PERIOD_SECS));
+    }
+
+    let key = rate_limit_key(&limit.publisher, &limit.topic);
+
+    // A changed limit starts with a full bucket
+    BUCKETS.with(|p| p.borrow_mut().remove(&key));
+    RATE_LIMITS.with(|p| p.borrow_mut().insert(key, limit));
+
+    Ok("Success: The rate limit was stored".to_string())
+}
+
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn rate_limit_remove(publisher: Principal, topic: String) -> Result<String, String> {
+    let key = rate_limit_key(&publisher, &topic);
+
+    BUCKETS.with(|p| p.borrow_mut().remove(&key));
+
+    match RATE_LIMITS.with(|p| p.borrow_mut().remove(&key)) {
+        Some(_) => Ok("Success: The rate limit was removed".to_string()),
+        None => Err("There is no rate limit for this publisher and topic".to_string()),
+    }
+}
+
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn rate_limit_list() -> Vec<RateLimit> {
+    RATE_LIMITS.with(|p| p.borrow().iter().map(|(_, v)| v).collect())
+}
+
+#[ic_cdk_macros::query]
+fn rate_limit_usage() -> Vec<RateLimitUsage> {
+    let caller = ic_cdk::caller();
+    let all = ic_cdk::api::is_controller(&caller);
+    let now = ic_cdk::api::time();
+
+    let limits: Vec<(String, RateLimit)> = RATE_LIMITS.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, v)| all || v.publisher == caller)
+            .collect()
+    });
+
+    BUCKETS.with(|p| {
+        let buckets = p.borrow();
+
+        limits.into_iter()
+            .map(|(key, limit)| {
+                let bucket = buckets.get(&key);
+
+                RateLimitUsage {
+                    tokens: bucket.map_or(limit.capacity, |b| b.tokens(&limit, now)),
+                    allowed: bucket.map_or(0, |b| b.allowed),
+                    rejected: bucket.map_or(0, |b| b.rejected),
+                    limit,
+                }
+            })
+            .collect()
+    })
+}
+
+/// Takes `count` tokens from the publisher's bucket for the topic, if there
+/// is a limit for them. Either all of them are taken or none.
+pub fn rate_limit_take(publisher: &Principal, topic: &str, count: u64) -> Result<(), IntakeError> {
+    let key = rate_limit_key(publisher, topic);
+    let limit = match RATE_LIMITS.with(|p| p.borrow().get(&key)) {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+
+    // Would never fit in the bucket, so waiting does not help
+    if count > limit.capacity {
+        return Err(IntakeError::Invalid(format!(
+            "The batch has {} messages for topic {}, the rate limit allows at most {} per {} seconds",
+            count, topic, limit.capacity, limit.period_secs,
+        )));
+    }
+
+    let now = ic_cdk::api::time();
+
+    BUCKETS.with(|p| {
+        let mut buckets = p.borrow_mut();
+        let bucket = buckets.entry(key).or_insert_with(|| Bucket::new(&limit, now));
+
+        let retry_after_secs = match bucket.take(&limit, count, now) {
+            Ok(()) => return Ok(()),
+            Err(retry_after_secs) => retry_after_secs,
+        };
+
+        // At most one log entry per bucket and period, so a flood of rejected
+        // messages does not turn into a flood of calls to the logging canister
+        let log_due = bucket.logged_at
+            .is_none_or(|at| now.saturating_sub(at) >= limit.period_secs.saturating_mul(1_000_000_000));
+
+        if log_due {
+            violation_log(&limit, bucket.rejected_unlogged, retry_after_secs);
+            bucket.rejected_unlogged = 0;
+            bucket.logged_at = Some(now);
+        }
+
+        Err(IntakeError::RateLimited {
+            reason: format!("The rate limit of {} messages per {} seconds for topic {} is used up", limit.capacity, limit.period_secs, topic),
+            retry_after_secs,
+        })
+    })
+}
+
+/// Puts back tokens taken for messages that were not queued after all.
+pub fn rate_limit_refund(publisher: &Principal, topic: &str, count: u64) {
+    let key = rate_limit_key(publisher, topic);
+    let limit = match RATE_LIMITS.with(|p| p.borrow().get(&key)) {
+        Some(limit) => limit,
+        None => return,
+    };
+
+    BUCKETS.with(|p| {
+        if let Some(bucket) = p.borrow_mut().get_mut(&key) {
+            bucket.refund(&limit, count);
+        }
+    });
+}
+
+/// Principals cannot contain a slash, so the key is unambiguous.
+fn rate_limit_key(publisher: &Principal, topic: &str) -> String {
+    format!("{}/{}", publisher, topic)
+}
+
+fn token_units(limit: &RateLimit) -> u128 {
+    limit.period_secs as u128 * NANOS_PER_SEC
+}
+
+fn bucket_full(limit: &RateLimit) -> u128 {
+    limit.capacity as u128 * token_units(limit)
+}
+
+fn bucket_level(limit: &RateLimit, bucket: &Bucket, now: u64) -> u128 {
+    let elapsed = now.saturating_sub(bucket.updated_at) as u128;
+    (bucket.level + elapsed * limit.capacity as u128).min(bucket_full(limit))
+}
+
+fn violation_log(limit: &RateLimit, rejected: u64, retry_after_secs: u64) {
+    let logging_canister = match logging_canister() {
+        Some(logging_canister) => logging_canister,
+        None => return,
+    };
+
+    let message = format!("Rate limit exceeded by {} on topic {}", limit.publisher, limit.topic);
+    let data = format!(
+        "capacity={} period_secs={} rejected={} retry_after_secs={}",
+        limit.capacity, limit.period_secs, rejected, retry_after_secs,
+    );
+
+    ic_cdk::spawn(async move {
+        let result: CallResult<(Result<String, String>, )> = ic_cdk::call(
+            logging_canister,
+            "log",
+            (LOG_TYPE_WARNING, LOG_ORIGIN.to_string(), message, data),
+        ).await;
+
+        if let Err((code, message)) = result {
+            ic_cdk::print(format!("Could not log a rate limit violation: {:?}: {}", code, message));
+        }
+    });
+}
+
+fn logging_canister() -> Option<Principal> {
+    queue_settings().logging_canister
+        .or_else(|| canister_settings_get("logging_backend".to_string()).map(|s| s.canister_id))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: u64 = 1_000_000_000;
+
+    fn limit(capacity: u64, period_secs: u64) -> RateLimit {
+        RateLimit {
+            publisher: Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap(),
+            topic: "orders".to_string(),
+            capacity,
+            period_secs,
+        }
+    }
+
+    #[test]
+    fn a_new_bucket_is_full_and_empties() {
+        let limit = limit(3, 60);
+        let mut bucket = Bucket::new(&limit, 0);
+
+        assert_eq!(bucket.tokens(&limit, 0), 3);
+        assert_eq!(bucket.take(&limit, 2, 0), Ok(()));
+        assert_eq!(bucket.take(&limit, 1, 0), Ok(()));
+        assert!(bucket.take(&limit, 1, 0).is_err());
+
+        assert_eq!((bucket.allowed, bucket.rejected, bucket.rejected_unlogged), (3, 1, 1));
+    }
+
+    #[test]
+    fn tokens_refill_at_capacity_per_period() {
+        let limit = limit(3, 60);
+        let mut bucket = Bucket::new(&limit, 0);
+        bucket.take(&limit, 3, 0).unwrap();
+
+        // One token every 20 seconds
+        assert_eq!(bucket.tokens(&limit, 19 * SEC), 0);
+        assert_eq!(bucket.tokens(&limit, 20 * SEC), 1);
+        assert_eq!(bucket.tokens(&limit, 40 * SEC), 2);
+        assert_eq!(bucket.take(&limit, 2, 40 * SEC), Ok(()));
+    }
+
+    #[test]
+    fn refill_is_clamped_to_capacity() {
+        let limit = limit(3, 60);
+        let mut bucket = Bucket::new(&limit, 0);
+        bucket.take(&limit, 1, 0).unwrap();
+
+        assert_eq!(bucket.tokens(&limit, 3600 * SEC), 3);
+        assert!(bucket.take(&limit, 4, 3600 * SEC).is_err());
+        assert_eq!(bucket.take(&limit, 3, 3600 * SEC), Ok(()));
+    }
+
+    #[test]
+    fn retry_after_is_the_time_until_enough_tokens() {
+        let limit = limit(3, 60);
+        let mut bucket = Bucket::new(&limit, 0);
+        bucket.take(&limit, 3, 0).unwrap();
+
+        assert_eq!(bucket.take(&limit, 1, 0), Err(20));
+        assert_eq!(bucket.take(&limit, 2, 0), Err(40));
+        assert_eq!(bucket.take(&limit, 1, 15 * SEC), Err(5));
+
+        // Never less than a second, even when the token is nanoseconds away
+        assert_eq!(bucket.take(&limit, 1, 20 * SEC - 1), Err(1));
+    }
+
+    #[test]
+    fn a_rejected_take_leaves_the_level_alone() {
+        let limit = limit(3, 60);
+        let mut bucket = Bucket::new(&limit, 0);
+        bucket.take(&limit, 2, 0).unwrap();
+
+        assert!(bucket.take(&limit, 2, 0).is_err());
+        assert_eq!(bucket.take(&limit, 1, 0), Ok(()));
+    }
+
+    #[test]
+    fn refunds_restore_tokens_up_to_capacity() {
+        let limit = limit(3, 60);
+        let mut bucket = Bucket::new(&limit, 0);
+        bucket.take(&limit, 2, 0).unwrap();
+
+        bucket.refund(&limit, 2);
+        assert_eq!(bucket.tokens(&limit, 0), 3);
+        assert_eq!(bucket.allowed, 0);
+
+        bucket.refund(&limit, 5);
+        assert_eq!(bucket.tokens(&limit, 0), 3);
+    }
+}
@@ -0,0 +1,98 @@
+use candid::Principal;
+use ic_stable_structures::Storable;
+use std::borrow::Cow;
+use bus_common::record::record_version;
+use crate::types::{CanisterIds, CanisterSettings, Idcache, QueueConfig};
+use crate::{DEFAULT_MAX_DEPTH, DEFAULT_MAX_TOPIC_DEPTH, DEFAULT_MAX_PUBLISHER_DEPTH};
+
+
+/******************************************************/
+//
+//  STORAGE FIXTURES
+//
+//  Hex encoded records as they were written by earlier
+//  releases. Every fixture must keep decoding.
+//
+/******************************************************/
+
+const CANISTER_IDS_V0: &str = include_str!("../fixtures/canister_ids_v0.hex");
+const CANISTER_SETTINGS_V0: &str = include_str!("../fixtures/canister_settings_v0.hex");
+const IDCACHE_V0: &str = include_str!("../fixtures/idcache_v0.hex");
+
+fn fixture(hex: &str) -> Vec<u8> {
+    let hex = hex.trim();
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// Decodes a stored record, writes it back and decodes the result, which is
+/// what the `post_upgrade` migrations do.
+fn upgrade<T: Storable>(hex: &str) -> T {
+    let bytes = fixture(hex);
+    assert_eq!(record_version(&bytes), Ok(0));
+
+    let record = T::from_bytes(Cow::Owned(bytes));
+    let rewritten = record.to_bytes().into_owned();
+    assert_eq!(record_version(&rewritten), Ok(1));
+
+    T::from_bytes(Cow::Owned(rewritten))
+}
+
+#[test]
+fn whitelist_v0_upgrades_and_drops_invalid_ids() {
+    let canister_ids: CanisterIds = upgrade(CANISTER_IDS_V0);
+
+    assert_eq!(canister_ids.ids, vec![Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()]);
+}
+
+#[test]
+fn canister_settings_v0_upgrades() {
+    let settings: CanisterSettings = upgrade(CANISTER_SETTINGS_V0);
+
+    assert_eq!(settings.canister_id, Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap());
+}
+
+#[test]
+fn subscriber_cache_v0_upgrades() {
+    let cache: Idcache = upgrade(IDCACHE_V0);
+
+    assert_eq!(cache.ids, vec!["sub-1", "sub-2"]);
+    assert_eq!(cache.topic, "orders");
+    assert_eq!(cache.timestamp, 1700000000000000000);
+}
+
+#[test]
+fn queue_config_round_trips_at_version_1() {
+    let config = QueueConfig {
+        interval_secs: 5,
+        chunk_size: 100,
+        ..QueueConfig::default()
+    };
+    let bytes = config.to_bytes().into_owned();
+    assert_eq!(record_version(&bytes), Ok(1));
+
+    let decoded = QueueConfig::from_bytes(Cow::Owned(bytes));
+
+    assert_eq!(decoded.interval_secs, 5);
+    assert_eq!(decoded.chunk_size, 100);
+    assert_eq!(decoded.max_depth, DEFAULT_MAX_DEPTH);
+    assert_eq!(decoded.max_topic_depth, DEFAULT_MAX_TOPIC_DEPTH);
+    assert_eq!(decoded.max_publisher_depth, DEFAULT_MAX_PUBLISHER_DEPTH);
+}
+
+#[test]
+fn records_larger_than_the_old_bound_round_trip() {
+    let cache = Idcache {
+        ids: (0..200).map(|i| format!("subscriber-{}", i)).collect(),
+        topic: "orders".to_string(),
+        timestamp: 0,
+    };
+
+    let decoded = Idcache::from_bytes(cache.to_bytes());
+
+    assert_eq!(decoded.ids.len(), 200);
+}
+
@@ -1,9 +1,14 @@
-use candid::{CandidType, Decode, Encode};
+use candid::{CandidType, Decode, Principal};
 use serde::{Deserialize, Serialize};
 use ic_stable_structures::{Storable, storable::Bound};
+use bus_common::record::{record_encode, record_decode, VersionedRecord};
 use std::{borrow::Cow};
-
-const MAX_VALUE_SIZE: u32 = 1024;
+use crate::{
+    DEFAULT_INTERVAL_SECS, DEFAULT_CHUNK_SIZE, DEFAULT_CACHE_TTL_SECS,
+    DEFAULT_MAX_CONCURRENCY, DEFAULT_MAX_PER_SUBSCRIBER,
+    DEFAULT_MAX_DEPTH, DEFAULT_MAX_TOPIC_DEPTH, DEFAULT_MAX_PUBLISHER_DEPTH,
+    DEFAULT_CIRCUIT_THRESHOLD, DEFAULT_CIRCUIT_COOLDOWN_SECS,
+};
 
 
 /******************************************************/
@@ -27,15 +32,278 @@ pub struct Message {
     pub value: String,
 }
 
+/// Passed to `init`, and optionally to `post_upgrade` to change the stored
+/// settings. Omitted fields keep their stored or default value.
 #[derive(CandidType, Deserialize, Serialize)]
 pub struct InitArgs {
-    pub registry_canister: String,
+    pub registry_canister: Principal,
+    pub logging_canister: Option<Principal>,
+    pub interval_secs: Option<u64>,
+    pub chunk_size: Option<u64>,
+}
+
+/// Canisters the queue talks to, persisted from the init args.
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct QueueSettings {
+    pub registry_canister: Option<Principal>,
+    pub logging_canister: Option<Principal>,
+}
+
+/// Consumer settings, changed through `queue_config_set`.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct QueueConfig {
+    pub interval_secs: u64,
+    pub chunk_size: u64,
+    pub cache_ttl_secs: u64,
+    pub max_concurrency: u64,
+    pub max_per_subscriber: u64,
+    pub max_depth: u64,
+    pub max_topic_depth: u64,
+    pub max_publisher_depth: u64,
+    pub circuit_threshold: u64,
+    pub circuit_cooldown_secs: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            interval_secs: DEFAULT_INTERVAL_SECS,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            cache_ttl_secs: DEFAULT_CACHE_TTL_SECS,
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            max_per_subscriber: DEFAULT_MAX_PER_SUBSCRIBER,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_topic_depth: DEFAULT_MAX_TOPIC_DEPTH,
+            max_publisher_depth: DEFAULT_MAX_PUBLISHER_DEPTH,
+            circuit_threshold: DEFAULT_CIRCUIT_THRESHOLD,
+            circuit_cooldown_secs: DEFAULT_CIRCUIT_COOLDOWN_SECS,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Default)]
+pub struct QueueConfigUpdate {
+    pub interval_secs: Option<u64>,
+    pub chunk_size: Option<u64>,
+    pub cache_ttl_secs: Option<u64>,
+    pub max_concurrency: Option<u64>,
+    pub max_per_subscriber: Option<u64>,
+    pub max_depth: Option<u64>,
+    pub max_topic_depth: Option<u64>,
+    pub max_publisher_depth: Option<u64>,
+    pub circuit_threshold: Option<u64>,
+    pub circuit_cooldown_secs: Option<u64>,
+}
+
+/// Queue entry. The publisher is kept so its share of the queue is released
+/// when the entry leaves it; messages queued by the bus itself have none.
+#[derive(Deserialize, Serialize)]
+pub struct QueuedMessage {
+    pub id: String,
+    pub topic: String,
+    pub value: String,
+    pub publisher: Option<Principal>,
+    pub queued_at: u64,
+}
+
+/// A message as handed to batch callbacks.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Envelope {
+    pub id: String,
+    pub topic: String,
+    pub value: String,
+    pub publisher: Option<Principal>,
+    pub queued_at: u64,
+}
+
+impl From<QueuedMessage> for Envelope {
+    fn from(queued: QueuedMessage) -> Self {
+        Envelope {
+            id: queued.id,
+            topic: queued.topic,
+            value: queued.value,
+            publisher: queued.publisher,
+            queued_at: queued.queued_at,
+        }
+    }
+}
+
+/// Returned by batch callbacks, one per envelope.
+#[derive(CandidType, Deserialize)]
+pub struct EnvelopeResult {
+    pub id: String,
+    pub result: Result<String, String>,
+}
+
+/// Opt-in to batched delivery. Messages for the subscriber are collected
+/// and sent to `callback` once `max_batch_size` are pending or the oldest
+/// has waited `max_wait_secs`.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct BatchSettings {
+    pub callback: String,
+    pub max_batch_size: u64,
+    pub max_wait_secs: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BatchStatus {
+    pub subscriber_id: String,
+    pub settings: Option<BatchSettings>,
+    pub pending: u64,
+    pub waiting_since: Option<u64>,
+}
+
+/// Why `intake` did not queue a message. The retry hints are estimates
+/// based on how fast the consumer drains the queue.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum IntakeError {
+    Invalid(String),
+    NotWhitelisted,
+    QueueFull { reason: String, retry_after_secs: u64 },
+    RateLimited { reason: String, retry_after_secs: u64 },
+}
+
+impl std::fmt::Display for IntakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntakeError::Invalid(reason) => write!(f, "{}", reason),
+            IntakeError::NotWhitelisted => write!(f, "Fail: The sending canister is not whitelisted"),
+            IntakeError::QueueFull { reason, retry_after_secs }
+            | IntakeError::RateLimited { reason, retry_after_secs } => {
+                write!(f, "{}, retry in {} seconds", reason, retry_after_secs)
+            },
+        }
+    }
+}
+
+/// How `intake_batch` handles a message that cannot be queued.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum IntakeBatchMode {
+    /// Queue every message or none of them
+    AllOrNothing,
+    /// Queue what can be queued and report each message on its own
+    PerItem,
+}
+
+/// A batch that was rejected as a whole. `index` points at the message that
+/// caused it, if a single one did.
+#[derive(CandidType, Deserialize, Debug)]
+pub struct IntakeBatchError {
+    pub index: Option<u64>,
+    pub error: IntakeError,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Delivery health of a subscriber, see `circuit.rs`. Also reported to the
+/// registry whenever the state changes.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct CircuitStatus {
+    pub subscriber_id: String,
+    pub state: CircuitState,
+    pub failures: u64,
+    pub opened_at: Option<u64>,
+    pub buffered: u64,
+    pub dropped: u64,
+    pub updated_at: u64,
+}
+
+/// Token bucket for one publisher and topic, see `rate_limit.rs`.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct RateLimit {
+    pub publisher: Principal,
+    pub topic: String,
+    pub capacity: u64,
+    pub period_secs: u64,
+}
+
+/// Counts since the limit was set or the canister was upgraded.
+#[derive(CandidType, Deserialize)]
+pub struct RateLimitUsage {
+    pub limit: RateLimit,
+    pub tokens: u64,
+    pub allowed: u64,
+    pub rejected: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum CyclesUsageKind {
+    Topic,
+    Publisher,
+    Subscriber,
+}
+
+/// Cycles spent on deliveries within one hour for one topic, publisher or
+/// subscriber, and the number of messages they were spent on.
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct CyclesUsageRecord {
+    pub cycles: u128,
+    pub messages: u64,
+}
+
+/// Selects the hours that overlap `from..to`, in nanoseconds since the epoch.
+/// `hourly` returns every hour on its own instead of one sum per key.
+#[derive(CandidType, Deserialize)]
+pub struct CyclesUsageRequest {
+    pub from: u64,
+    pub to: u64,
+    pub kind: Option<CyclesUsageKind>,
+    pub key: Option<String>,
+    pub hourly: bool,
+}
+
+/// Estimated from the fee schedule, see `cycles.rs`, not measured.
+#[derive(CandidType, Deserialize)]
+pub struct CyclesUsage {
+    pub kind: CyclesUsageKind,
+    pub key: String,
+    pub from: u64,
+    pub to: u64,
+    pub cycles: u128,
+    pub messages: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct QueueDepth {
+    pub total: u64,
+    pub topics: Vec<TopicDepth>,
+    pub publishers: Vec<PublisherDepth>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TopicDepth {
+    pub topic: String,
+    pub depth: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PublisherDepth {
+    pub publisher: Principal,
+    pub depth: u64,
+}
+
+/// Deliveries that were started and have not been answered yet.
+#[derive(CandidType, Deserialize)]
+pub struct InFlightStatus {
+    pub total: u64,
+    pub subscribers: Vec<SubscriberInFlight>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SubscriberInFlight {
+    pub subscriber_id: String,
+    pub in_flight: u64,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Subscribers {
     pub id: String,
-    pub canister_id: String,
+    pub canister_id: Principal,
     pub callback: String,
     pub name: String,
     pub description: String,
@@ -53,9 +321,24 @@ pub struct Topics {
     pub active: bool,
 }
 
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct ListArgs {
+    pub start_after: Option<String>,
+    pub limit: Option<u64>,
+    pub active_only: Option<bool>,
+    pub name_prefix: Option<String>,
+    pub topic: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+}
+
 #[derive(CandidType, Deserialize, Serialize)]
 pub struct CanisterIds {
-    pub ids: Vec<String>,
+    pub ids: Vec<Principal>,
 }
 
 #[derive(CandidType, Deserialize, Serialize)]
@@ -67,10 +350,10 @@ pub struct Idcache {
 
 
 
-#[derive(CandidType, Deserialize, Serialize)]
+#[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct SubscriberCache {
     pub id: String,
-    pub canister_id: String,
+    pub canister_id: Principal,
     pub callback: String,
     pub name: String,
     pub description: String,
@@ -83,79 +366,268 @@ pub struct SubscriberCache {
 
 #[derive(CandidType, Deserialize, Serialize)]
 pub struct CanisterSettings {
-    pub canister_id: String,
+    pub canister_id: Principal,
+}
+
+// CONFIG ///////////////////////////////////////////
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ConfigEntry {
+    pub canister_name: String,
+    pub canister_id: Principal,
+}
+
+/// Versioned canister settings published by the registry.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ConfigDocument {
+    pub version: u64,
+    pub published_at: u64,
+    pub canisters: Vec<ConfigEntry>,
 }
 
 
+/******************************************************/
+//
+//  LEGACY
+//
+//  Encodings written before canister IDs were stored as
+//  principals, read as version 0 of their records
+//
+/******************************************************/
+
+#[derive(CandidType, Deserialize)]
+pub struct CanisterIdsLegacy {
+    pub ids: Vec<String>,
+}
+
+/// Whitelist entries that do not parse could never match a caller, so they
+/// are dropped.
+impl From<CanisterIdsLegacy> for CanisterIds {
+    fn from(v: CanisterIdsLegacy) -> Self {
+        CanisterIds {
+            ids: v.ids.iter().filter_map(|id| Principal::from_text(id).ok()).collect(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CanisterSettingsLegacy {
+    pub canister_id: String,
+}
+
+/// Unparsable IDs become the anonymous principal, which the migration removes.
+impl From<CanisterSettingsLegacy> for CanisterSettings {
+    fn from(v: CanisterSettingsLegacy) -> Self {
+        CanisterSettings {
+            canister_id: Principal::from_text(&v.canister_id).unwrap_or(Principal::anonymous()),
+        }
+    }
+}
 
 
 /******************************************************/
 //
 //  STORABLES
 //
+//  Records are stored through `bus_common::record`, which
+//  prefixes them with their schema version. Version 0 is the
+//  plain Candid encoding written before versioning.
+//
 /******************************************************/
 
 // QUEUE ///////////////////////////////////////////
-impl Storable for CanisterIds {
+impl VersionedRecord for QueueSettings {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("Unknown queue settings record version {}", version))
+    }
+}
+
+impl Storable for QueueSettings {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(record_encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl VersionedRecord for QueueConfig {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("Unknown queue config record version {}", version))
+    }
+}
+
+impl Storable for QueueConfig {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(record_encode(self))
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
 }
 
-impl Storable for Idcache {
+impl VersionedRecord for BatchSettings {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("Unknown batch settings record version {}", version))
+    }
+}
+
+impl Storable for BatchSettings {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(record_encode(self))
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
 }
 
-impl Storable for SubscriberCache {
+impl VersionedRecord for RateLimit {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("Unknown rate limit record version {}", version))
+    }
+}
+
+impl Storable for RateLimit {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(record_encode(self))
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
 }
 
-impl Storable for CanisterSettings {
+impl VersionedRecord for CyclesUsageRecord {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("Unknown cycles usage record version {}", version))
+    }
+}
+
+impl Storable for CyclesUsageRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(record_encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl VersionedRecord for CanisterIds {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Decode!(payload, Self)
+                .or_else(|_| Decode!(payload, CanisterIdsLegacy).map(Self::from))
+                .map_err(|err| err.to_string()),
+            _ => Err(format!("Unknown whitelist record version {}", version)),
+        }
+    }
+}
+
+impl Storable for CanisterIds {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(record_encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl VersionedRecord for Idcache {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Decode!(payload, Self).map_err(|err| err.to_string()),
+            _ => Err(format!("Unknown subscriber cache record version {}", version)),
+        }
+    }
+}
+
+impl Storable for Idcache {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(record_encode(self))
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
 }
 
+impl VersionedRecord for SubscriberCache {
+    const VERSION: u8 = 1;
 
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Decode!(payload, Self).map_err(|err| err.to_string()),
+            _ => Err(format!("Unknown subscriber data cache record version {}", version)),
+        }
+    }
+}
 
+impl Storable for SubscriberCache {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(record_encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
 
+impl VersionedRecord for CanisterSettings {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Decode!(payload, Self)
+                .or_else(|_| Decode!(payload, CanisterSettingsLegacy).map(Self::from))
+                .map_err(|err| err.to_string()),
+            _ => Err(format!("Unknown canister settings record version {}", version)),
+        }
+    }
+}
+
+impl Storable for CanisterSettings {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(record_encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
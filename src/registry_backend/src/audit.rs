@@ -0,0 +1,309 @@
+use ic_stable_structures::StableBTreeMap;
+use std::cell::Cell;
+use crate::types::{
+    AuditArgs, AuditEntity, AuditEntry, AuditPage, IndexKey,
+    Canisters, Namespaces, Subscribers, Topic,
+};
+use crate::relations::subscriber_delete;
+use crate::utils::{caller_is_controller, limit_clamp, MAX_PAGE_SCAN};
+use crate::{
+    Memory, MAP_TOPIC, MAP_NAMESPACE, MAP_SUBSCRIBER, MAP_CANISTER,
+    MAP_AUDIT_LOG, MAP_AUDIT_BY_ENTITY, MAP_AUDIT_BY_CALLER,
+};
+
+thread_local! {
+    static AUDIT_ACTIVE: Cell<bool> = const { Cell::new(false) };
+}
+
+
+/******************************************************/
+//
+//  AUDIT
+//
+//  Every mutating endpoint appends an entry with the caller,
+//  the endpoint and a summary of the entity before and after
+//  the call. Entries are numbered in the order they are
+//  written, so sequence order is also time order.
+//
+//  audit_log           Page through entries, optionally filtered
+//  audit_entry         Lookup an entry by sequence number
+//
+/******************************************************/
+
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn audit_log(args: Option<AuditArgs>) -> AuditPage {
+    let args = args.unwrap_or_default();
+    let start = args.start_after.map_or(0, |seq| seq + 1);
+
+    if let Some(entity_id) = &args.entity_id {
+        let seqs = MAP_AUDIT_BY_ENTITY.with(|p| index_seqs(&p.borrow(), entity_id, start));
+        return audit_page(seqs.into_iter().filter_map(audit_get), &args);
+    }
+
+    if let Some(caller) = &args.caller {
+        let seqs = MAP_AUDIT_BY_CALLER.with(|p| index_seqs(&p.borrow(), &caller.to_text(), start));
+        return audit_page(seqs.into_iter().filter_map(audit_get), &args);
+    }
+
+    MAP_AUDIT_LOG.with(|p| audit_page(p.borrow().range(start..).map(|(_, v)| v), &args))
+}
+
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn audit_entry(seq: u64) -> Option<AuditEntry> {
+    audit_get(seq)
+}
+
+/// Runs a mutating call on an existing entity and records it if it succeeds.
+/// Calls made while another call is being audited are part of that entry.
+pub fn audited<T, E>(
+    endpoint: &str,
+    entity: AuditEntity,
+    entity_id: String,
+    call: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    if AUDIT_ACTIVE.with(|a| a.get()) {
+        return call();
+    }
+
+    let before = entity_summary(entity, &entity_id);
+    let result = audit_scope(call);
+
+    if result.is_ok() {
+        audit_record(endpoint, entity, &entity_id, before, entity_summary(entity, &entity_id));
+    }
+    result
+}
+
+/// Like `audited`, for calls that create an entity and return its new ID.
+pub fn audited_create<E>(
+    endpoint: &str,
+    entity: AuditEntity,
+    call: impl FnOnce() -> Result<String, E>,
+) -> Result<String, E> {
+    if AUDIT_ACTIVE.with(|a| a.get()) {
+        return call();
+    }
+
+    let result = audit_scope(call);
+
+    if let Ok(entity_id) = &result {
+        audit_record(endpoint, entity, entity_id, None, entity_summary(entity, entity_id));
+    }
+    result
+}
+
+pub fn audit_record(
+    endpoint: &str,
+    entity: AuditEntity,
+    entity_id: &str,
+    before: Option<String>,
+    after: Option<String>,
+) {
+    let seq = MAP_AUDIT_LOG.with(|p| p.borrow().last_key_value().map_or(1, |(k, _)| k + 1));
+    let caller = ic_cdk::caller();
+
+    MAP_AUDIT_BY_ENTITY.with(|p| p.borrow_mut().insert(seq_key(entity_id, seq), ()));
+    MAP_AUDIT_BY_CALLER.with(|p| p.borrow_mut().insert(seq_key(&caller.to_text(), seq), ()));
+    MAP_AUDIT_LOG.with(|p| p.borrow_mut().insert(seq, AuditEntry {
+        seq,
+        timestamp: ic_cdk::api::time(),
+        caller,
+        endpoint: endpoint.to_string(),
+        entity,
+        entity_id: entity_id.to_string(),
+        before,
+        after,
+    }));
+}
+
+/// Cascading deletes remove subscribers that the entry of the endpoint does
+/// not describe, so each of them gets an entry of its own.
+pub fn subscriber_delete_audited(endpoint: &str, subscriber_id: &str) {
+    if let Some(subscriber) = subscriber_delete(subscriber_id) {
+        audit_record(endpoint, AuditEntity::Subscriber, subscriber_id, Some(subscriber.audit_summary()), None);
+    }
+}
+
+fn audit_scope<R>(call: impl FnOnce() -> R) -> R {
+    AUDIT_ACTIVE.with(|a| a.set(true));
+    let result = call();
+    AUDIT_ACTIVE.with(|a| a.set(false));
+    result
+}
+
+fn audit_get(seq: u64) -> Option<AuditEntry> {
+    MAP_AUDIT_LOG.with(|p| p.borrow().get(&seq))
+}
+
+/// Index IDs are zero padded so they sort in sequence order.
+fn seq_key(key: &str, seq: u64) -> IndexKey {
+    IndexKey { key: key.to_string(), id: format!("{:020}", seq) }
+}
+
+fn index_seqs(
+    map: &StableBTreeMap<IndexKey, (), Memory>,
+    key: &str,
+    start: u64,
+) -> Vec<u64> {
+    map.range(seq_key(key, start)..)
+        .take_while(|(k, _)| k.key == key)
+        .take(MAX_PAGE_SCAN as usize)
+        .filter_map(|(k, _)| k.id.parse().ok())
+        .collect()
+}
+
+fn audit_matches(entry: &AuditEntry, args: &AuditArgs) -> bool {
+    args.entity_id.as_ref().is_none_or(|id| &entry.entity_id == id)
+        && args.caller.is_none_or(|caller| entry.caller == caller)
+        && args.from.is_none_or(|from| entry.timestamp >= from)
+}
+
+/// Stops at the first entry after `to`, since later entries are newer. A
+/// full page only has a `next` if another entry follows it; a scan that hits
+/// the limit always has one.
+fn audit_page(entries: impl Iterator<Item = AuditEntry>, args: &AuditArgs) -> AuditPage {
+    let limit = limit_clamp(args.limit);
+    let mut entries = entries
+        .take_while(|entry| args.to.is_none_or(|to| entry.timestamp <= to))
+        .peekable();
+    let mut items: Vec<AuditEntry> = Vec::new();
+    let mut scanned: u64 = 0;
+
+    while let Some(entry) = entries.next() {
+        scanned += 1;
+        let seq = entry.seq;
+
+        if audit_matches(&entry, args) {
+            items.push(entry);
+        }
+
+        if scanned == MAX_PAGE_SCAN {
+            return AuditPage { items, next: Some(seq) };
+        }
+
+        if items.len() == limit {
+            let next = entries.peek().is_some().then_some(seq);
+            return AuditPage { items, next };
+        }
+    }
+
+    AuditPage { items, next: None }
+}
+
+fn entity_summary(entity: AuditEntity, entity_id: &str) -> Option<String> {
+    let id = entity_id.to_string();
+
+    match entity {
+        AuditEntity::Namespace => MAP_NAMESPACE.with(|p| p.borrow().get(&id)).map(|v| v.audit_summary()),
+        AuditEntity::Topic => MAP_TOPIC.with(|p| p.borrow().get(&id)).map(|v| v.audit_summary()),
+        AuditEntity::Subscriber => MAP_SUBSCRIBER.with(|p| p.borrow().get(&id)).map(|v| v.audit_summary()),
+        AuditEntity::Canister => MAP_CANISTER.with(|p| p.borrow().get(&id)).map(|v| v.audit_summary()),
+        AuditEntity::Registry => None,
+    }
+}
+
+pub trait AuditSummary {
+    fn audit_summary(&self) -> String;
+}
+
+impl AuditSummary for Namespaces {
+    fn audit_summary(&self) -> String {
+        format!(
+            "name={} active={} subscribers={} version={}",
+            self.name, self.active, self.subscribers.len(), self.version.unwrap_or(0),
+        )
+    }
+}
+
+impl AuditSummary for Topic {
+    fn audit_summary(&self) -> String {
+        format!(
+            "name={} active={} namespaces=[{}] version={}",
+            self.name, self.active, self.namespaces.join(","), self.version.unwrap_or(0),
+        )
+    }
+}
+
+impl AuditSummary for Subscribers {
+    fn audit_summary(&self) -> String {
+        format!(
+            "canister_id={} callback={} topic={} namespace={} active={} version={}",
+            self.canister_id, self.callback, self.topic, self.namespace, self.active, self.version.unwrap_or(0),
+        )
+    }
+}
+
+impl AuditSummary for Canisters {
+    fn audit_summary(&self) -> String {
+        format!(
+            "canister_id={} name={} active={} version={}",
+            self.canister_id, self.name, self.active, self.version.unwrap_or(0),
+        )
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    fn entries(count: u64) -> impl Iterator<Item = AuditEntry> {
+        (1..=count).map(|seq| AuditEntry {
+            seq,
+            timestamp: seq * 10,
+            caller: Principal::anonymous(),
+            endpoint: "topic_update".to_string(),
+            entity: AuditEntity::Topic,
+            entity_id: format!("t-{}", seq % 2),
+            before: None,
+            after: None,
+        })
+    }
+
+    fn seqs(page: &AuditPage) -> Vec<u64> {
+        page.items.iter().map(|e| e.seq).collect()
+    }
+
+    #[test]
+    fn a_full_page_with_more_entries_has_next() {
+        let page = audit_page(entries(5), &AuditArgs { limit: Some(2), ..Default::default() });
+
+        assert_eq!(seqs(&page), vec![1, 2]);
+        assert_eq!(page.next, Some(2));
+    }
+
+    #[test]
+    fn an_exactly_filled_page_has_no_next() {
+        let page = audit_page(entries(2), &AuditArgs { limit: Some(2), ..Default::default() });
+
+        assert_eq!(seqs(&page), vec![1, 2]);
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn entries_after_to_do_not_count_as_more() {
+        let page = audit_page(entries(5), &AuditArgs { to: Some(20), limit: Some(2), ..Default::default() });
+
+        assert_eq!(seqs(&page), vec![1, 2]);
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn filters_apply_before_the_limit() {
+        let args = AuditArgs { entity_id: Some("t-1".to_string()), limit: Some(2), ..Default::default() };
+        let page = audit_page(entries(5), &args);
+
+        assert_eq!(seqs(&page), vec![1, 3]);
+        assert_eq!(page.next, Some(3));
+    }
+
+    #[test]
+    fn the_scan_limit_always_sets_next() {
+        let args = AuditArgs { entity_id: Some("none".to_string()), ..Default::default() };
+        let page = audit_page(entries(MAX_PAGE_SCAN), &args);
+
+        assert!(page.items.is_empty());
+        assert_eq!(page.next, Some(MAX_PAGE_SCAN));
+    }
+}
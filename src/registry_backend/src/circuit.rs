@@ -0,0 +1,59 @@
+use crate::types::{CircuitState, CircuitStatus, SubscriberView};
+use crate::{MAP_CANISTER, MAP_SUBSCRIBER, MAP_SUBSCRIBER_CIRCUIT};
+
+/// Name the queue canister is registered under.
+const QUEUE_CANISTER_NAME: &str = "queue_backend";
+
+
+/******************************************************/
+//
+//  SUBSCRIBER CIRCUITS
+//
+//  The queue stops calling subscribers that keep failing
+//  and reports every change of their circuit here, so the
+//  registry can show it next to the subscriber.
+//
+//  subscriber_circuit_report   Store a circuit, called by the queue
+//  subscriber_view             A subscriber with its circuit
+//
+/******************************************************/
+
+/// Closed circuits are removed, since that is the default.
+#[ic_cdk_macros::update]
+fn subscriber_circuit_report(status: CircuitStatus) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let from_queue = MAP_CANISTER.with(|p| p.borrow().get(&QUEUE_CANISTER_NAME.to_string()))
+        .is_some_and(|q| q.canister_id == caller);
+
+    if !(from_queue || ic_cdk::api::is_controller(&caller)) {
+        return Err("Only the queue canister can report circuits".to_string());
+    }
+
+    if MAP_SUBSCRIBER.with(|p| !p.borrow().contains_key(&status.subscriber_id)) {
+        return Err("The subscriber does not exist".to_string());
+    }
+
+    let subscriber_id = status.subscriber_id.clone();
+
+    MAP_SUBSCRIBER_CIRCUIT.with(|p| {
+        let mut map = p.borrow_mut();
+
+        match status.state {
+            CircuitState::Closed => map.remove(&subscriber_id),
+            _ => map.insert(subscriber_id.clone(), status),
+        }
+    });
+
+    Ok(subscriber_id)
+}
+
+#[ic_cdk_macros::query]
+fn subscriber_view(subscriber_id: String) -> Option<SubscriberView> {
+    let mut subscriber = MAP_SUBSCRIBER.with(|p| p.borrow().get(&subscriber_id))?;
+    subscriber.id = subscriber_id.clone();
+
+    Some(SubscriberView {
+        subscriber,
+        circuit: MAP_SUBSCRIBER_CIRCUIT.with(|p| p.borrow().get(&subscriber_id)),
+    })
+}
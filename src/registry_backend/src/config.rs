@@ -0,0 +1,327 @@
+use candid::Principal;
+use ic_cdk::api::call::CallResult;
+use std::time::Duration;
+use crate::audit::audit_record;
+use crate::types::{AuditEntity, ConfigDocument, ConfigEntry, ConfigPushState, ConfigTarget};
+use crate::utils::caller_is_controller;
+use crate::{MAP_CANISTER, MAP_CONFIG, MAP_CONFIG_TARGET};
+
+/// The registry does not push to itself.
+const CONFIG_SELF_NAME: &str = "registry_backend";
+
+/// Published documents kept for `config_document` lookups.
+const CONFIG_HISTORY: u64 = 16;
+
+/// Failed pushes are retried with a linearly growing delay until a target
+/// has failed this many times in a row.
+const CONFIG_MAX_ATTEMPTS: u32 = 5;
+const CONFIG_RETRY_SECS: u64 = 30;
+
+
+/******************************************************/
+//
+//  CONFIG DISTRIBUTION
+//
+//  Every service canister gets the IDs of its peers from one
+//  versioned document. The registry pushes it to each canister
+//  on its own, so an unreachable canister only delays itself,
+//  and canisters pull it when they start.
+//
+//  config_publish      Publish a new version if the canisters changed
+//  config_push         Push the current version to outdated canisters
+//  config_retry        Push the current version to one canister
+//  config_pull         Current version for the calling canister
+//  config_document     Lookup a published version
+//  config_status       Version and push state of every canister
+//
+//  Pushes and retry timers do not survive an upgrade, so
+//  config_targets_resume starts them again afterwards.
+//
+/******************************************************/
+
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn config_publish() -> u64 {
+    config_current().version
+}
+
+/// Pushes run concurrently and report back through `config_status`. Returns
+/// the names of the canisters a push was started for.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+pub fn config_push() -> Vec<String> {
+    let document = config_current();
+
+    let outdated: Vec<String> = MAP_CONFIG_TARGET.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, t)| t.applied_version != Some(document.version) && t.state != ConfigPushState::InFlight)
+            .map(|(k, _)| k)
+            .collect()
+    });
+
+    for canister_name in outdated.iter() {
+        target_push_start(canister_name);
+    }
+
+    audit_record(
+        "config_push",
+        AuditEntity::Registry,
+        "registry",
+        None,
+        Some(format!("version={} targets={}", document.version, outdated.len())),
+    );
+
+    outdated
+}
+
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn config_retry(canister_name: String) -> Result<String, String> {
+    config_current();
+
+    match MAP_CONFIG_TARGET.with(|p| p.borrow().get(&canister_name)) {
+        None => Err("The canister is not a configuration target".to_string()),
+        Some(target) if target.state == ConfigPushState::InFlight => {
+            Err("A push to the canister is already in flight".to_string())
+        },
+        Some(_) => {
+            target_push_start(&canister_name);
+            Ok(canister_name)
+        },
+    }
+}
+
+/// Called by service canisters on startup. Returning the document counts as
+/// delivering it, so the caller's target is marked as applied.
+#[ic_cdk_macros::update]
+fn config_pull() -> Result<ConfigDocument, String> {
+    let caller = ic_cdk::caller();
+    let document = config_current();
+
+    let canister_name = MAP_CONFIG_TARGET.with(|p| {
+        p.borrow()
+            .iter()
+            .find(|(_, t)| t.canister_id == caller)
+            .map(|(k, _)| k)
+    });
+
+    match canister_name {
+        Some(canister_name) => target_applied(&canister_name, document.version),
+        None if ic_cdk::api::is_controller(&caller) => {},
+        None => return Err("Only registered canisters can pull the configuration".to_string()),
+    }
+
+    Ok(document)
+}
+
+/// The latest version when `version` is omitted.
+#[ic_cdk_macros::query]
+fn config_document(version: Option<u64>) -> Option<ConfigDocument> {
+    MAP_CONFIG.with(|p| {
+        let map = p.borrow();
+
+        match version {
+            Some(version) => map.get(&version),
+            None => map.last_key_value().map(|(_, v)| v),
+        }
+    })
+}
+
+#[ic_cdk_macros::query]
+fn config_status() -> Vec<ConfigTarget> {
+    MAP_CONFIG_TARGET.with(|p| p.borrow().iter().map(|(_, v)| v).collect())
+}
+
+/// Returns the latest document, publishing a new version first when the
+/// active canisters differ from it, and brings the targets in line with the
+/// registered canisters.
+pub fn config_current() -> ConfigDocument {
+    let canisters: Vec<ConfigEntry> = MAP_CANISTER.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, v)| v.active)
+            .map(|(k, v)| ConfigEntry { canister_name: k, canister_id: v.canister_id })
+            .collect()
+    });
+
+    let latest = MAP_CONFIG.with(|p| p.borrow().last_key_value().map(|(_, v)| v));
+
+    let document = match latest {
+        Some(document) if document.canisters == canisters => document,
+        latest => {
+            let document = ConfigDocument {
+                version: latest.map_or(1, |d| d.version + 1),
+                published_at: ic_cdk::api::time(),
+                canisters,
+            };
+
+            MAP_CONFIG.with(|p| {
+                let mut map = p.borrow_mut();
+                map.insert(document.version, document.clone());
+
+                if document.version > CONFIG_HISTORY {
+                    map.remove(&(document.version - CONFIG_HISTORY));
+                }
+            });
+
+            audit_record(
+                "config_publish",
+                AuditEntity::Registry,
+                "registry",
+                None,
+                Some(format!("version={} canisters={}", document.version, document.canisters.len())),
+            );
+
+            document
+        },
+    };
+
+    targets_sync(&document);
+    document
+}
+
+fn targets_sync(document: &ConfigDocument) {
+    let now = ic_cdk::api::time();
+
+    MAP_CONFIG_TARGET.with(|p| {
+        let mut map = p.borrow_mut();
+
+        let removed: Vec<String> = map.iter()
+            .filter(|(k, _)| !document.canisters.iter().any(|c| &c.canister_name == k))
+            .map(|(k, _)| k)
+            .collect();
+
+        for canister_name in removed.iter() {
+            map.remove(canister_name);
+        }
+
+        for entry in document.canisters.iter().filter(|c| c.canister_name != CONFIG_SELF_NAME) {
+            match map.get(&entry.canister_name) {
+                // A canister that moved to another principal has applied nothing
+                Some(target) if target.canister_id == entry.canister_id => {
+                    if target.state == ConfigPushState::Applied && target.applied_version != Some(document.version) {
+                        map.insert(entry.canister_name.clone(), ConfigTarget {
+                            state: ConfigPushState::Pending,
+                            updated_at: now,
+                            ..target
+                        });
+                    }
+                },
+                _ => {
+                    map.insert(entry.canister_name.clone(), ConfigTarget {
+                        canister_name: entry.canister_name.clone(),
+                        canister_id: entry.canister_id,
+                        applied_version: None,
+                        state: ConfigPushState::Pending,
+                        attempts: 0,
+                        last_error: None,
+                        updated_at: now,
+                    });
+                },
+            }
+        }
+    });
+}
+
+fn target_update(canister_name: &str, update: impl FnOnce(&mut ConfigTarget)) -> Option<ConfigTarget> {
+    MAP_CONFIG_TARGET.with(|p| {
+        let mut map = p.borrow_mut();
+        let mut target = map.get(&canister_name.to_string())?;
+
+        update(&mut target);
+        target.updated_at = ic_cdk::api::time();
+        map.insert(canister_name.to_string(), target.clone());
+
+        Some(target)
+    })
+}
+
+fn target_applied(canister_name: &str, version: u64) {
+    target_update(canister_name, |t| {
+        t.applied_version = Some(version);
+        t.state = ConfigPushState::Applied;
+        t.attempts = 0;
+        t.last_error = None;
+    });
+}
+
+fn target_push_start(canister_name: &str) {
+    let target = target_update(canister_name, |t| t.state = ConfigPushState::InFlight);
+
+    if let Some(target) = target {
+        ic_cdk::spawn(target_push(target.canister_name, target.canister_id));
+    }
+}
+
+/// Always pushes the latest document, so a retry also delivers versions
+/// published after the failed push.
+async fn target_push(canister_name: String, canister_id: Principal) {
+    let document = match MAP_CONFIG.with(|p| p.borrow().last_key_value().map(|(_, v)| v)) {
+        Some(document) => document,
+        None => return,
+    };
+
+    let result: CallResult<(Result<u64, String>, )> =
+        ic_cdk::call(canister_id, "config_apply", (document, )).await;
+
+    let error = match result {
+        Ok((Ok(version), )) => return target_applied(&canister_name, version),
+        Ok((Err(err), )) => err,
+        Err((code, message)) => format!("{:?}: {}", code, message),
+    };
+
+    let target = target_update(&canister_name, |t| {
+        t.state = ConfigPushState::Failed;
+        t.attempts += 1;
+        t.last_error = Some(error);
+    });
+
+    if let Some(target) = target {
+        target_retry_schedule(target);
+    }
+}
+
+/// Retries a failed push after a delay that grows with its attempts, until
+/// it has failed `CONFIG_MAX_ATTEMPTS` times.
+fn target_retry_schedule(target: ConfigTarget) {
+    if target.attempts >= CONFIG_MAX_ATTEMPTS {
+        return;
+    }
+
+    let canister_name = target.canister_name;
+    let delay = Duration::from_secs(CONFIG_RETRY_SECS * target.attempts as u64);
+
+    ic_cdk_timers::set_timer(delay, move || {
+        let still_failed = MAP_CONFIG_TARGET.with(|p| p.borrow().get(&canister_name))
+            .is_some_and(|t| t.state == ConfigPushState::Failed);
+
+        if still_failed {
+            target_push_start(&canister_name);
+        }
+    });
+}
+
+/// Called from `post_upgrade`. A push that was in flight during the upgrade
+/// never reports back; it counts as failed without using up an attempt.
+/// Failed targets get their retry timer again.
+pub fn config_targets_resume() {
+    let targets: Vec<ConfigTarget> = MAP_CONFIG_TARGET.with(|p| {
+        p.borrow()
+            .iter()
+            .map(|(_, t)| t)
+            .filter(|t| matches!(t.state, ConfigPushState::InFlight | ConfigPushState::Failed))
+            .collect()
+    });
+
+    for target in targets.into_iter() {
+        let target = match target.state {
+            ConfigPushState::InFlight => target_update(&target.canister_name, |t| {
+                t.state = ConfigPushState::Failed;
+                t.last_error = Some("The push was interrupted by an upgrade".to_string());
+            }),
+            _ => Some(target),
+        };
+
+        if let Some(target) = target {
+            target_retry_schedule(target);
+        }
+    }
+}
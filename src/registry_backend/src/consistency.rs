@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use crate::index::{
+    name_indexes_rebuild, name_indexes_out_of_sync,
+    subscriber_indexes_rebuild, subscriber_indexes_out_of_sync,
+};
+use crate::relations::{
+    namespace_subscriber_add, namespace_subscriber_remove, namespace_least_loaded,
+    subscriber_assign_namespace, subscriber_delete,
+};
+use crate::audit::audit_record;
+use crate::types::{AuditEntity, RegistryIssue, RegistryReport};
+use crate::utils::{caller_is_controller, principal_check};
+use crate::validation::{check_name, MAX_NAME_LEN};
+use crate::{MAP_TOPIC, MAP_NAMESPACE, MAP_SUBSCRIBER, MAP_CANISTER};
+
+
+/******************************************************/
+//
+//  CONSISTENCY
+//
+//  registry_verify     Report every inconsistency in the registry
+//  registry_repair     Fix the reported inconsistencies
+//
+/******************************************************/
+
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn registry_verify() -> Vec<RegistryIssue> {
+    registry_issues()
+}
+
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn registry_repair(dry_run: bool) -> RegistryReport {
+    let issues = registry_issues();
+    let mut actions: Vec<String> = Vec::new();
+
+    // Reassignments pick namespaces by their indexed subscriber counts
+    if !dry_run {
+        subscriber_indexes_rebuild();
+    }
+
+    for issue in issues.iter() {
+        actions.push(repair(issue, dry_run));
+    }
+
+    if !dry_run {
+        name_indexes_rebuild();
+        subscriber_indexes_rebuild();
+        audit_record("registry_repair", AuditEntity::Registry, "registry", None, Some(format!("issues={}", issues.len())));
+    }
+
+    RegistryReport {
+        issues,
+        actions,
+        dry_run,
+    }
+}
+
+pub fn registry_issues() -> Vec<RegistryIssue> {
+    let mut issues: Vec<RegistryIssue> = Vec::new();
+
+    let topics: BTreeMap<String, _> = MAP_TOPIC.with(|p| p.borrow().iter().collect());
+    let namespaces: BTreeMap<String, _> = MAP_NAMESPACE.with(|p| p.borrow().iter().collect());
+    let subscribers: BTreeMap<String, _> = MAP_SUBSCRIBER.with(|p| p.borrow().iter().collect());
+
+    // Topics
+    let mut topic_names: BTreeMap<&String, Vec<String>> = BTreeMap::new();
+
+    for (topic_id, topic) in topics.iter() {
+        for namespace_id in topic.namespaces.iter() {
+            if !namespaces.contains_key(namespace_id) {
+                issues.push(RegistryIssue::TopicNamespaceMissing {
+                    topic_id: topic_id.clone(),
+                    namespace_id: namespace_id.clone(),
+                });
+            }
+        }
+        topic_names.entry(&topic.name).or_default().push(topic_id.clone());
+    }
+
+    for (name, topic_ids) in topic_names {
+        if topic_ids.len() > 1 {
+            issues.push(RegistryIssue::TopicNameDuplicate { name: name.clone(), topic_ids });
+        }
+    }
+
+    // Namespaces
+    let mut namespace_names: BTreeMap<&String, Vec<String>> = BTreeMap::new();
+
+    for (namespace_id, ns) in namespaces.iter() {
+        for subscriber_id in ns.subscribers.iter() {
+            let assigned = subscribers
+                .get(subscriber_id)
+                .is_some_and(|s| &s.namespace == namespace_id);
+
+            if !assigned {
+                issues.push(RegistryIssue::NamespaceSubscriberStale {
+                    namespace_id: namespace_id.clone(),
+                    subscriber_id: subscriber_id.clone(),
+                });
+            }
+        }
+        namespace_names.entry(&ns.name).or_default().push(namespace_id.clone());
+    }
+
+    for (name, namespace_ids) in namespace_names {
+        if namespace_ids.len() > 1 {
+            issues.push(RegistryIssue::NamespaceNameDuplicate { name: name.clone(), namespace_ids });
+        }
+    }
+
+    // Subscribers
+    for (subscriber_id, subscriber) in subscribers.iter() {
+        if principal_check(&subscriber.canister_id).is_err() {
+            issues.push(RegistryIssue::SubscriberPrincipalInvalid {
+                subscriber_id: subscriber_id.clone(),
+                canister_id: subscriber.canister_id,
+            });
+        }
+
+        let topic = match topics.get(&subscriber.topic) {
+            Some(topic) => topic,
+            None => {
+                issues.push(RegistryIssue::SubscriberTopicMissing {
+                    subscriber_id: subscriber_id.clone(),
+                    topic_id: subscriber.topic.clone(),
+                });
+                continue;
+            }
+        };
+
+        match namespaces.get(&subscriber.namespace) {
+            None => issues.push(RegistryIssue::SubscriberNamespaceMissing {
+                subscriber_id: subscriber_id.clone(),
+                namespace_id: subscriber.namespace.clone(),
+            }),
+            Some(ns) => {
+                if !topic.namespaces.iter().any(|i| i == &subscriber.namespace) {
+                    issues.push(RegistryIssue::SubscriberNamespaceNotInTopic {
+                        subscriber_id: subscriber_id.clone(),
+                        namespace_id: subscriber.namespace.clone(),
+                        topic_id: subscriber.topic.clone(),
+                    });
+                }
+
+                if !ns.subscribers.iter().any(|i| i == subscriber_id) {
+                    issues.push(RegistryIssue::NamespaceSubscriberUnlisted {
+                        namespace_id: subscriber.namespace.clone(),
+                        subscriber_id: subscriber_id.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    // Canisters
+    MAP_CANISTER.with(|p| {
+        for (canister_name, canister) in p.borrow().iter() {
+            if principal_check(&canister.canister_id).is_err() {
+                issues.push(RegistryIssue::CanisterPrincipalInvalid {
+                    canister_name,
+                    canister_id: canister.canister_id,
+                });
+            }
+        }
+    });
+
+    // Indexes
+    for index in name_indexes_out_of_sync().into_iter().chain(subscriber_indexes_out_of_sync()) {
+        issues.push(RegistryIssue::IndexOutOfSync { index });
+    }
+
+    issues
+}
+
+/// Applies the fix for one issue, or only describes it when `dry_run` is set.
+/// Issues are computed up front, so every fix first checks that it still
+/// applies.
+fn repair(issue: &RegistryIssue, dry_run: bool) -> String {
+    match issue {
+        RegistryIssue::TopicNamespaceMissing { topic_id, namespace_id } => {
+            if !dry_run {
+                MAP_TOPIC.with(|p| {
+                    let mut map = p.borrow_mut();
+
+                    if let Some(mut topic) = map.get(topic_id) {
+                        topic.namespaces.retain(|i| i != namespace_id);
+                        map.insert(topic_id.clone(), topic);
+                    }
+                });
+            }
+            format!("Remove namespace {} from topic {}", namespace_id, topic_id)
+        }
+        RegistryIssue::TopicNameDuplicate { name, topic_ids } => {
+            let mut unrepairable: Vec<&String> = Vec::new();
+
+            for topic_id in topic_ids.iter().skip(1) {
+                let renamed = match name_deduplicated(name, topic_id) {
+                    Some(renamed) => renamed,
+                    None => {
+                        unrepairable.push(topic_id);
+                        continue;
+                    }
+                };
+
+                if !dry_run {
+                    MAP_TOPIC.with(|p| {
+                        let mut map = p.borrow_mut();
+
+                        if let Some(mut topic) = map.get(topic_id) {
+                            topic.name = renamed;
+                            map.insert(topic_id.clone(), topic);
+                        }
+                    });
+                }
+            }
+
+            let action = format!("Keep topic {} as {} and suffix the other topics' names with their ID", topic_ids[0], name);
+            duplicate_action(action, "topic", &unrepairable)
+        }
+        RegistryIssue::NamespaceNameDuplicate { name, namespace_ids } => {
+            let mut unrepairable: Vec<&String> = Vec::new();
+
+            for namespace_id in namespace_ids.iter().skip(1) {
+                let renamed = match name_deduplicated(name, namespace_id) {
+                    Some(renamed) => renamed,
+                    None => {
+                        unrepairable.push(namespace_id);
+                        continue;
+                    }
+                };
+
+                if !dry_run {
+                    MAP_NAMESPACE.with(|p| {
+                        let mut map = p.borrow_mut();
+
+                        if let Some(mut ns) = map.get(namespace_id) {
+                            ns.name = renamed;
+                            map.insert(namespace_id.clone(), ns);
+                        }
+                    });
+                }
+            }
+
+            let action = format!("Keep namespace {} as {} and suffix the other namespaces' names with their ID", namespace_ids[0], name);
+            duplicate_action(action, "namespace", &unrepairable)
+        }
+        RegistryIssue::SubscriberPrincipalInvalid { subscriber_id, .. }
+        | RegistryIssue::SubscriberTopicMissing { subscriber_id, .. } => {
+            if !dry_run {
+                subscriber_delete(subscriber_id);
+            }
+            format!("Remove subscriber {}", subscriber_id)
+        }
+        RegistryIssue::SubscriberNamespaceMissing { subscriber_id, .. }
+        | RegistryIssue::SubscriberNamespaceNotInTopic { subscriber_id, .. } => {
+            let topic_id = match MAP_SUBSCRIBER.with(|p| p.borrow().get(subscriber_id)) {
+                Some(subscriber) => subscriber.topic,
+                None => return format!("Skip subscriber {}, it was already removed", subscriber_id),
+            };
+
+            match namespace_least_loaded(&topic_id) {
+                Some(namespace_id) => {
+                    if !dry_run {
+                        let _ = subscriber_assign_namespace(subscriber_id, &namespace_id);
+                    }
+                    format!("Assign subscriber {} to namespace {}", subscriber_id, namespace_id)
+                }
+                None => {
+                    if !dry_run {
+                        subscriber_delete(subscriber_id);
+                    }
+                    format!("Remove subscriber {}, its topic has no namespaces", subscriber_id)
+                }
+            }
+        }
+        RegistryIssue::NamespaceSubscriberStale { namespace_id, subscriber_id } => {
+            if !dry_run {
+                namespace_subscriber_remove(namespace_id, subscriber_id);
+            }
+            format!("Remove subscriber {} from namespace {}", subscriber_id, namespace_id)
+        }
+        RegistryIssue::NamespaceSubscriberUnlisted { namespace_id, subscriber_id } => {
+            if !dry_run {
+                let assigned = MAP_SUBSCRIBER.with(|p| p.borrow().get(subscriber_id))
+                    .is_some_and(|s| &s.namespace == namespace_id);
+
+                if assigned {
+                    namespace_subscriber_add(namespace_id, subscriber_id);
+                }
+            }
+            format!("Add subscriber {} to namespace {}", subscriber_id, namespace_id)
+        }
+        RegistryIssue::CanisterPrincipalInvalid { canister_name, .. } => {
+            if !dry_run {
+                MAP_CANISTER.with(|p| p.borrow_mut().remove(canister_name));
+            }
+            format!("Remove canister {}", canister_name)
+        }
+        RegistryIssue::IndexOutOfSync { index } => {
+            format!("Rebuild index {}", index)
+        }
+    }
+}
+
+/// The name of a duplicate suffixed with its ID. The name is shortened so the
+/// result still fits `MAX_NAME_LEN`; `None` if it would not pass `check_name`,
+/// for example because the stored name has invalid characters.
+fn name_deduplicated(name: &str, id: &str) -> Option<String> {
+    let room = MAX_NAME_LEN.saturating_sub(id.len() + 1);
+    let mut base = String::new();
+
+    for c in name.chars() {
+        if base.len() + c.len_utf8() > room {
+            break;
+        }
+        base.push(c);
+    }
+
+    let renamed = format!("{}-{}", base, id);
+    let mut errors = Vec::new();
+    check_name(&mut errors, "name", &renamed);
+
+    errors.is_empty().then_some(renamed)
+}
+
+fn duplicate_action(action: String, entity: &str, unrepairable: &[&String]) -> String {
+    if unrepairable.is_empty() {
+        return action;
+    }
+
+    let ids: Vec<&str> = unrepairable.iter().map(|id| id.as_str()).collect();
+    format!("{}; {} {} cannot be renamed automatically and must be renamed by hand", action, entity, ids.join(", "))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_names_keep_their_full_name() {
+        assert_eq!(name_deduplicated("orders", "b3c1"), Some("orders-b3c1".to_string()));
+    }
+
+    #[test]
+    fn long_names_are_shortened_to_fit() {
+        let name = "n".repeat(MAX_NAME_LEN);
+        let id = "0f8fad5b-d9cb-469f-a165-70867728950e";
+        let renamed = name_deduplicated(&name, id).unwrap();
+
+        assert_eq!(renamed.len(), MAX_NAME_LEN);
+        assert!(renamed.ends_with(&format!("-{}", id)));
+    }
+
+    #[test]
+    fn names_that_cannot_be_fixed_are_reported() {
+        assert_eq!(name_deduplicated("order book", "b3c1"), None);
+        assert_eq!(name_deduplicated("orders", &"i".repeat(MAX_NAME_LEN)), None);
+    }
+}
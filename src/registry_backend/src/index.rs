@@ -0,0 +1,261 @@
+use ic_stable_structures::StableBTreeMap;
+use std::ops::Bound;
+use crate::types::{IndexKey, ListArgs, Page, Subscribers};
+use crate::utils::paginate;
+use crate::{
+    Memory, MAP_TOPIC, MAP_NAMESPACE, MAP_SUBSCRIBER,
+    MAP_TOPIC_NAME, MAP_NAMESPACE_NAME,
+    MAP_SUBSCRIBER_BY_TOPIC, MAP_SUBSCRIBER_BY_NAMESPACE, MAP_SUBSCRIBER_BY_CANISTER,
+};
+
+
+/******************************************************/
+//
+//  NAME INDEXES
+//
+//  topic_id_by_name            Lookup a topic ID by name
+//  topic_name_index_set        Point a topic name at an ID
+//  topic_name_index_remove     Remove a topic name
+//  topic_name_index_move       Move a topic ID to a new name
+//  namespace_id_by_name        Lookup a namespace ID by name
+//  namespace_name_index_set    Point a namespace name at an ID
+//  namespace_name_index_remove Remove a namespace name
+//  namespace_name_index_move   Move a namespace ID to a new name
+//  name_indexes_rebuild        Rebuild the name indexes from the maps
+//  name_indexes_out_of_sync    Name indexes that disagree with the maps
+//
+/******************************************************/
+
+pub fn topic_id_by_name(name: &str) -> Option<String> {
+    MAP_TOPIC_NAME.with(|p| p.borrow().get(&name.to_string()))
+}
+
+pub fn topic_name_index_set(name: &str, topic_id: &str) {
+    MAP_TOPIC_NAME.with(|p| p.borrow_mut().insert(name.to_string(), topic_id.to_string()));
+}
+
+pub fn topic_name_index_remove(name: &str) {
+    MAP_TOPIC_NAME.with(|p| p.borrow_mut().remove(&name.to_string()));
+}
+
+pub fn topic_name_index_move(old_name: &str, new_name: &str, topic_id: &str) {
+    if old_name == new_name {
+        return;
+    }
+
+    if topic_id_by_name(old_name).as_deref() == Some(topic_id) {
+        topic_name_index_remove(old_name);
+    }
+    topic_name_index_set(new_name, topic_id);
+}
+
+pub fn namespace_id_by_name(name: &str) -> Option<String> {
+    MAP_NAMESPACE_NAME.with(|p| p.borrow().get(&name.to_string()))
+}
+
+pub fn namespace_name_index_set(name: &str, namespace_id: &str) {
+    MAP_NAMESPACE_NAME.with(|p| p.borrow_mut().insert(name.to_string(), namespace_id.to_string()));
+}
+
+pub fn namespace_name_index_remove(name: &str) {
+    MAP_NAMESPACE_NAME.with(|p| p.borrow_mut().remove(&name.to_string()));
+}
+
+pub fn namespace_name_index_move(old_name: &str, new_name: &str, namespace_id: &str) {
+    if old_name == new_name {
+        return;
+    }
+
+    if namespace_id_by_name(old_name).as_deref() == Some(namespace_id) {
+        namespace_name_index_remove(old_name);
+    }
+    namespace_name_index_set(new_name, namespace_id);
+}
+
+/// Registries created before the indexes existed have records but no index
+/// entries. When names collide the first ID in key order keeps the name.
+pub fn name_indexes_rebuild() {
+    MAP_TOPIC_NAME.with(|p| p.borrow_mut().clear_new());
+    MAP_TOPIC.with(|p| {
+        for (k, v) in p.borrow().iter() {
+            if topic_id_by_name(&v.name).is_none() {
+                topic_name_index_set(&v.name, &k);
+            }
+        }
+    });
+
+    MAP_NAMESPACE_NAME.with(|p| p.borrow_mut().clear_new());
+    MAP_NAMESPACE.with(|p| {
+        for (k, v) in p.borrow().iter() {
+            if namespace_id_by_name(&v.name).is_none() {
+                namespace_name_index_set(&v.name, &k);
+            }
+        }
+    });
+}
+
+pub fn name_indexes_out_of_sync() -> Vec<String> {
+    let mut out_of_sync: Vec<String> = Vec::new();
+
+    let topic_names_valid = MAP_TOPIC_NAME.with(|p| {
+        p.borrow().iter().all(|(name, id)| {
+            MAP_TOPIC.with(|t| t.borrow().get(&id)).is_some_and(|topic| topic.name == name)
+        })
+    }) && MAP_TOPIC.with(|p| p.borrow().iter().all(|(_, v)| topic_id_by_name(&v.name).is_some()));
+
+    if !topic_names_valid {
+        out_of_sync.push("topic_name".to_string());
+    }
+
+    let namespace_names_valid = MAP_NAMESPACE_NAME.with(|p| {
+        p.borrow().iter().all(|(name, id)| {
+            MAP_NAMESPACE.with(|n| n.borrow().get(&id)).is_some_and(|ns| ns.name == name)
+        })
+    }) && MAP_NAMESPACE.with(|p| p.borrow().iter().all(|(_, v)| namespace_id_by_name(&v.name).is_some()));
+
+    if !namespace_names_valid {
+        out_of_sync.push("namespace_name".to_string());
+    }
+
+    out_of_sync
+}
+
+
+/******************************************************/
+//
+//  SUBSCRIBER INDEXES
+//
+//  subscriber_insert               Store a subscriber and index it
+//  subscriber_remove               Remove a subscriber and its index entries
+//  subscriber_ids_by_topic         Subscriber IDs of a topic
+//  subscriber_ids_by_namespace     Subscriber IDs of a namespace
+//  subscriber_ids_by_canister      Subscriber IDs of a canister
+//  subscribers_page_by_topic       Page through a topic's subscribers
+//  subscriber_indexes_rebuild      Rebuild the subscriber indexes from the map
+//  subscriber_indexes_out_of_sync  Subscriber indexes that disagree with the map
+//
+/******************************************************/
+
+type IndexMap = StableBTreeMap<IndexKey, (), Memory>;
+
+fn index_key(key: &str, id: &str) -> IndexKey {
+    IndexKey { key: key.to_string(), id: id.to_string() }
+}
+
+fn index_ids(map: &IndexMap, key: &str) -> Vec<String> {
+    map.range(index_key(key, "")..)
+        .take_while(|(k, _)| k.key == key)
+        .map(|(k, _)| k.id)
+        .collect()
+}
+
+fn subscriber_index_insert(id: &str, subscriber: &Subscribers) {
+    MAP_SUBSCRIBER_BY_TOPIC.with(|p| p.borrow_mut().insert(index_key(&subscriber.topic, id), ()));
+    MAP_SUBSCRIBER_BY_NAMESPACE.with(|p| p.borrow_mut().insert(index_key(&subscriber.namespace, id), ()));
+    MAP_SUBSCRIBER_BY_CANISTER.with(|p| p.borrow_mut().insert(index_key(&subscriber.canister_id.to_text(), id), ()));
+}
+
+fn subscriber_index_remove(id: &str, subscriber: &Subscribers) {
+    MAP_SUBSCRIBER_BY_TOPIC.with(|p| p.borrow_mut().remove(&index_key(&subscriber.topic, id)));
+    MAP_SUBSCRIBER_BY_NAMESPACE.with(|p| p.borrow_mut().remove(&index_key(&subscriber.namespace, id)));
+    MAP_SUBSCRIBER_BY_CANISTER.with(|p| p.borrow_mut().remove(&index_key(&subscriber.canister_id.to_text(), id)));
+}
+
+/// All writes to `MAP_SUBSCRIBER` go through here so the indexes are updated
+/// in the same message as the record.
+pub fn subscriber_insert(id: &str, subscriber: Subscribers) -> Option<Subscribers> {
+    let previous = MAP_SUBSCRIBER.with(|p| p.borrow_mut().insert(id.to_string(), subscriber.clone()));
+
+    if let Some(previous) = &previous {
+        subscriber_index_remove(id, previous);
+    }
+    subscriber_index_insert(id, &subscriber);
+
+    previous
+}
+
+pub fn subscriber_remove(id: &str) -> Option<Subscribers> {
+    let previous = MAP_SUBSCRIBER.with(|p| p.borrow_mut().remove(&id.to_string()));
+
+    if let Some(previous) = &previous {
+        subscriber_index_remove(id, previous);
+    }
+
+    previous
+}
+
+pub fn subscriber_ids_by_topic(topic_id: &str) -> Vec<String> {
+    MAP_SUBSCRIBER_BY_TOPIC.with(|p| index_ids(&p.borrow(), topic_id))
+}
+
+pub fn subscriber_ids_by_namespace(namespace_id: &str) -> Vec<String> {
+    MAP_SUBSCRIBER_BY_NAMESPACE.with(|p| index_ids(&p.borrow(), namespace_id))
+}
+
+pub fn subscriber_ids_by_canister(canister_id: &str) -> Vec<String> {
+    MAP_SUBSCRIBER_BY_CANISTER.with(|p| index_ids(&p.borrow(), canister_id))
+}
+
+pub fn subscribers_page_by_topic<T>(
+    topic_id: &str,
+    args: &ListArgs,
+    keep: impl Fn(&String, &Subscribers) -> bool,
+    to_item: impl Fn(String, Subscribers) -> T,
+) -> Page<T> {
+    let start = match &args.start_after {
+        Some(id) => Bound::Excluded(index_key(topic_id, id)),
+        None => Bound::Included(index_key(topic_id, "")),
+    };
+
+    MAP_SUBSCRIBER_BY_TOPIC.with(|i| {
+        MAP_SUBSCRIBER.with(|p| {
+            let subscribers = p.borrow();
+            let index = i.borrow();
+            let entries = index
+                .range((start, Bound::Unbounded))
+                .take_while(|(k, _)| k.key == topic_id)
+                .filter_map(|(k, _)| subscribers.get(&k.id).map(|v| (k.id, v)));
+
+            paginate(entries, args, keep, to_item)
+        })
+    })
+}
+
+pub fn subscriber_indexes_rebuild() {
+    MAP_SUBSCRIBER_BY_TOPIC.with(|p| p.borrow_mut().clear_new());
+    MAP_SUBSCRIBER_BY_NAMESPACE.with(|p| p.borrow_mut().clear_new());
+    MAP_SUBSCRIBER_BY_CANISTER.with(|p| p.borrow_mut().clear_new());
+
+    MAP_SUBSCRIBER.with(|p| {
+        for (k, v) in p.borrow().iter() {
+            subscriber_index_insert(&k, &v);
+        }
+    });
+}
+
+fn subscriber_index_in_sync(index: &IndexMap, field: fn(&Subscribers) -> String) -> bool {
+    MAP_SUBSCRIBER.with(|p| {
+        let subscribers = p.borrow();
+
+        index.len() == subscribers.len()
+            && subscribers.iter().all(|(k, v)| index.contains_key(&index_key(&field(&v), &k)))
+    })
+}
+
+pub fn subscriber_indexes_out_of_sync() -> Vec<String> {
+    let mut out_of_sync: Vec<String> = Vec::new();
+
+    if !MAP_SUBSCRIBER_BY_TOPIC.with(|i| subscriber_index_in_sync(&i.borrow(), |s| s.topic.clone())) {
+        out_of_sync.push("subscriber_by_topic".to_string());
+    }
+
+    if !MAP_SUBSCRIBER_BY_NAMESPACE.with(|i| subscriber_index_in_sync(&i.borrow(), |s| s.namespace.clone())) {
+        out_of_sync.push("subscriber_by_namespace".to_string());
+    }
+
+    if !MAP_SUBSCRIBER_BY_CANISTER.with(|i| subscriber_index_in_sync(&i.borrow(), |s| s.canister_id.to_text())) {
+        out_of_sync.push("subscriber_by_canister".to_string());
+    }
+
+    out_of_sync
+}
@@ -1,71 +1,183 @@
 use candid::{Principal};
 
 use ic_stable_structures::{
-    DefaultMemoryImpl, 
     StableBTreeMap,
-    memory_manager::MemoryId,
-    memory_manager::MemoryManager,
-    memory_manager::VirtualMemory,
+    StableCell,
 };
-use std::{cell::RefCell};
+use std::{cell::RefCell, ops::Bound};
 use types::{
     Namespace, Namespaces, 
     Topic, Topics, Subscriber, Subscribers, 
     ResultResponse, CallStringResponse, 
     CallSubscribersResponse, CallSubscriberResponse,
-    Canisters
+    Canisters, IndexKey, ListArgs, Page,
+    NamespaceUpdate, TopicUpdate, SubscriberUpdate, CanisterUpdate,
+    DeleteMode, RegistryError, AuditEntity, AuditEntry,
+    ConfigDocument, ConfigTarget, CircuitStatus,
+};
+use relations::{
+    namespace_subscriber_add, namespace_in_topic, subscriber_assign_namespace,
+    subscriber_delete, topic_ids_by_namespace, namespace_detach,
+    namespace_least_loaded, namespace_evacuate, topic_subscribers_rebalance,
+    topic_namespace_add, topic_namespace_remove,
 };
 
-use utils::{create_uuid, get_variable_type};
+use utils::{
+    create_uuid, get_variable_type,
+    paginate, page_start, name_matches, active_matches,
+    version_check, version_next, caller_is_controller,
+};
 use ic_cdk::print;
+use migration::{layout_migrate, migrations_run, schema_version_init};
+use validation::{
+    check_id, check_name, check_label, check_description, check_callback,
+    check_principal, check_list_len, validation_result, MAX_TOPIC_NAMESPACES,
+};
+use memory::{
+    memory_get, Memory,
+    MEMORY_TOPIC, MEMORY_NAMESPACE, MEMORY_SUBSCRIBER, MEMORY_CANISTER,
+    MEMORY_TOPIC_NAME, MEMORY_NAMESPACE_NAME,
+    MEMORY_SUBSCRIBER_BY_TOPIC, MEMORY_SUBSCRIBER_BY_NAMESPACE, MEMORY_SUBSCRIBER_BY_CANISTER,
+    MEMORY_SCHEMA_VERSION,
+    MEMORY_AUDIT_LOG, MEMORY_AUDIT_BY_ENTITY, MEMORY_AUDIT_BY_CALLER,
+    MEMORY_CONFIG, MEMORY_CONFIG_TARGET, MEMORY_SUBSCRIBER_CIRCUIT,
+};
+use audit::{audited, audited_create, subscriber_delete_audited};
+use config::{config_push, config_targets_resume};
+use bus_common::uuid::schedule_uuid_seed;
+use index::{
+    topic_id_by_name, topic_name_index_set, topic_name_index_remove, topic_name_index_move,
+    namespace_id_by_name, namespace_name_index_set, namespace_name_index_remove, namespace_name_index_move,
+    name_indexes_rebuild,
+    subscriber_insert,
+    subscriber_ids_by_topic, subscriber_ids_by_namespace, subscriber_ids_by_canister,
+    subscriber_indexes_rebuild, subscribers_page_by_topic,
+};
 
 mod types;
 mod utils;
+mod index;
+mod relations;
+mod consistency;
+mod migration;
+mod memory;
+mod validation;
+mod snapshot;
+mod audit;
+mod config;
+mod circuit;
+
+#[cfg(test)]
+mod tests;
 
 
 /******************************************************/
 //
-//  MEMORY MANAGER
+//  INIT
 //
 /******************************************************/
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+#[ic_cdk_macros::init]
+fn init() {
+    schedule_uuid_seed();
+    schema_version_init();
+}
 
-thread_local! {
-    static MEM_MAN_TOPIC: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+#[ic_cdk_macros::post_upgrade]
+fn post_upgrade() {
+    schedule_uuid_seed();
+    layout_migrate();
+    migrations_run();
+
+    let topics_len = MAP_TOPIC.with(|p| p.borrow().len());
+    let namespaces_len = MAP_NAMESPACE.with(|p| p.borrow().len());
+
+    if MAP_TOPIC_NAME.with(|p| p.borrow().len()) != topics_len
+        || MAP_NAMESPACE_NAME.with(|p| p.borrow().len()) != namespaces_len {
+        name_indexes_rebuild();
+    }
+
+    let subscribers_len = MAP_SUBSCRIBER.with(|p| p.borrow().len());
+
+    if MAP_SUBSCRIBER_BY_TOPIC.with(|p| p.borrow().len()) != subscribers_len
+        || MAP_SUBSCRIBER_BY_NAMESPACE.with(|p| p.borrow().len()) != subscribers_len
+        || MAP_SUBSCRIBER_BY_CANISTER.with(|p| p.borrow().len()) != subscribers_len {
+        subscriber_indexes_rebuild();
+    }
+
+    config_targets_resume();
+}
+
+/******************************************************/
+//
+//  MEMORY MANAGER
+//
+/******************************************************/
 
+thread_local! {
     static MAP_TOPIC: RefCell<StableBTreeMap<String, Topic, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEM_MAN_TOPIC.with(|m| m.borrow().get(MemoryId::new(0))),
-        )
+        StableBTreeMap::init(memory_get(MEMORY_TOPIC))
     );
 
-    static MEM_MAN_NAMESPACE: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-
     static MAP_NAMESPACE: RefCell<StableBTreeMap<String, Namespaces, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEM_MAN_NAMESPACE.with(|m| m.borrow().get(MemoryId::new(1))),
-        )
+        StableBTreeMap::init(memory_get(MEMORY_NAMESPACE))
     );
 
-    static MEM_MAN_SUBSCRIBER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-
     static MAP_SUBSCRIBER: RefCell<StableBTreeMap<String, Subscribers, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEM_MAN_SUBSCRIBER.with(|m| m.borrow().get(MemoryId::new(2))),
-        )
+        StableBTreeMap::init(memory_get(MEMORY_SUBSCRIBER))
     );
 
-    static MEM_MAN_CANISTER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-
     static MAP_CANISTER: RefCell<StableBTreeMap<String, Canisters, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEM_MAN_CANISTER.with(|m| m.borrow().get(MemoryId::new(3))),
-        )
+        StableBTreeMap::init(memory_get(MEMORY_CANISTER))
+    );
+
+    static MAP_TOPIC_NAME: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_TOPIC_NAME))
+    );
+
+    static MAP_NAMESPACE_NAME: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_NAMESPACE_NAME))
+    );
+
+    static MAP_SUBSCRIBER_BY_TOPIC: RefCell<StableBTreeMap<IndexKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_SUBSCRIBER_BY_TOPIC))
+    );
+
+    static MAP_SUBSCRIBER_BY_NAMESPACE: RefCell<StableBTreeMap<IndexKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_SUBSCRIBER_BY_NAMESPACE))
+    );
+
+    static MAP_SUBSCRIBER_BY_CANISTER: RefCell<StableBTreeMap<IndexKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_SUBSCRIBER_BY_CANISTER))
+    );
+
+    static SCHEMA_VERSION: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(memory_get(MEMORY_SCHEMA_VERSION), 0)
+            .expect("Could not initialize the schema version")
+    );
+
+    static MAP_AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_AUDIT_LOG))
+    );
+
+    static MAP_AUDIT_BY_ENTITY: RefCell<StableBTreeMap<IndexKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_AUDIT_BY_ENTITY))
+    );
+
+    static MAP_AUDIT_BY_CALLER: RefCell<StableBTreeMap<IndexKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_AUDIT_BY_CALLER))
+    );
+
+    static MAP_CONFIG: RefCell<StableBTreeMap<u64, ConfigDocument, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_CONFIG))
+    );
+
+    static MAP_CONFIG_TARGET: RefCell<StableBTreeMap<String, ConfigTarget, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_CONFIG_TARGET))
+    );
+
+    static MAP_SUBSCRIBER_CIRCUIT: RefCell<StableBTreeMap<String, CircuitStatus, Memory>> = RefCell::new(
+        StableBTreeMap::init(memory_get(MEMORY_SUBSCRIBER_CIRCUIT))
     );
 }
 
@@ -75,6 +187,8 @@ thread_local! {
 //
 //  namespace_register              Add namespace
 //  namespace_unregister            Remove namespace
+//  namespace_rename                Rename a namespace
+//  namespace_update                Update a namespace's details
 //  namespace                       Get a specific namespace's details
 //  namespaces                      Get all namespaces
 //  namespace_register_subscriber   Add subscriber to a namespace
@@ -85,54 +199,172 @@ thread_local! {
 /******************************************************/
 
 #[ic_cdk_macros::update]
-fn namespace_register(namespace: Namespace) -> Result<String, String> {
-    let id = create_uuid();
-
-    let ns = Namespaces {
-        id: id.clone(),
-        name: namespace.name,
-        description: namespace.description,
-        subscribers: namespace.subscribers,
-        active: namespace.active,
-    };
+fn namespace_register(namespace: Namespace) -> Result<String, RegistryError> {
+    audited_create("namespace_register", AuditEntity::Namespace, || {
+        let mut errors = Vec::new();
+        check_name(&mut errors, "name", &namespace.name);
+        check_description(&mut errors, "description", &namespace.description);
+        validation_result(errors)?;
+
+        if namespace_id_by_name(&namespace.name).is_some() {
+            return Err("A namespace with this name already exists".to_string().into());
+        }
+
+        if !namespace.subscribers.is_empty() {
+            return Err("Subscribers are assigned to a namespace when they are registered".to_string().into());
+        }
 
-    let res = MAP_NAMESPACE.with(|p| p.borrow_mut().insert(id.clone(), ns));
+        let id = create_uuid();
 
-    if get_variable_type(&res).contains("Namespace") {
-        Ok(id.clone().to_string())
-    } else {
-        Err("Could not register the namespace".to_string())
-    }   
+        let ns = Namespaces {
+            id: id.clone(),
+            name: namespace.name,
+            description: namespace.description,
+            subscribers: namespace.subscribers,
+            active: namespace.active,
+            version: Some(1),
+            updated_at: Some(ic_cdk::api::time()),
+        };
+
+        let name = ns.name.clone();
+        let res = MAP_NAMESPACE.with(|p| p.borrow_mut().insert(id.clone(), ns));
+
+        if get_variable_type(&res).contains("Namespace") {
+            namespace_name_index_set(&name, &id);
+            Ok(id.clone().to_string())
+        } else {
+            Err("Could not register the namespace".to_string().into())
+        }   
+    })
 }
 
 #[ic_cdk_macros::update]
-fn namespace_unregister(namespace_id: String) -> Result<String, String>  {
-    
-    if !MAP_NAMESPACE.with(|p| p.borrow().contains_key(&namespace_id)) {
-        return Err("The namespace was not found".to_string());
-    }
+fn namespace_unregister(namespace_id: String, mode: Option<DeleteMode>) -> Result<String, String>  {
+    audited("namespace_unregister", AuditEntity::Namespace, namespace_id.clone(), || {
+        if !MAP_NAMESPACE.with(|p| p.borrow().contains_key(&namespace_id)) {
+            return Err("The namespace was not found".to_string());
+        }
 
-    let res = MAP_NAMESPACE.with(|p| {p.borrow_mut().remove(&namespace_id)});
+        let mode = mode.unwrap_or(DeleteMode::Restrict);
+        let topic_ids = topic_ids_by_namespace(&namespace_id);
 
-    if get_variable_type(&res).contains("Namespace") {
-        Ok(namespace_id.to_string())
-    } else {
-        Err("Could not unregister the namespace".to_string())
-    }   
+        if mode == DeleteMode::Restrict && !topic_ids.is_empty() {
+            return Err(format!("The namespace is still assigned to {} topic(s)", topic_ids.len()));
+        }
+
+        if mode == DeleteMode::Cascade {
+            namespace_detach(&namespace_id);
+        }
+
+        // Subscribers are moved to another active namespace of their topic, only
+        // the ones with nowhere to go block (Restrict) or are removed (Cascade)
+        let stranded = subscriber_ids_by_namespace(&namespace_id)
+            .iter()
+            .filter(|id| {
+                MAP_SUBSCRIBER.with(|p| p.borrow().get(*id))
+                    .and_then(|s| namespace_least_loaded(&s.topic))
+                    .is_none()
+            })
+            .count();
+
+        if mode == DeleteMode::Restrict && stranded > 0 {
+            return Err(format!("{} subscriber(s) have no other active namespace to move to", stranded));
+        }
+
+        for subscriber_id in namespace_evacuate(&namespace_id, None).iter() {
+            subscriber_delete_audited("namespace_unregister", subscriber_id);
+        }
+
+        let res = MAP_NAMESPACE.with(|p| {p.borrow_mut().remove(&namespace_id)});
+
+        if let Some(ns) = res {
+            if namespace_id_by_name(&ns.name) == Some(namespace_id.clone()) {
+                namespace_name_index_remove(&ns.name);
+            }
+            Ok(namespace_id.to_string())
+        } else {
+            Err("Could not unregister the namespace".to_string())
+        }   
+    })
 }
 
 #[ic_cdk_macros::update]
-fn namespace_register_subscriber(namespace_id: String, subscriber_id: String) -> Result<String, String> { 
-    let mut ns: Namespaces = namespace(namespace_id.clone()).unwrap();
-    ns.subscribers.push(subscriber_id.clone());
+fn namespace_rename(namespace_id: String, name: String) -> Result<String, RegistryError> {
+    audited("namespace_rename", AuditEntity::Namespace, namespace_id.clone(), || {
+        let ns = match namespace(namespace_id.clone()) {
+            Some(ns) => ns,
+            None => return Err("The namespace was not found".to_string().into()),
+        };
 
-    let res = MAP_NAMESPACE.with(|p| p.borrow_mut().insert(namespace_id.clone(), ns));
+        namespace_update(namespace_id, NamespaceUpdate {
+            name: Some(name),
+            description: None,
+            active: None,
+            expected_version: ns.version.unwrap_or(0),
+        })
+    })
+}
 
-    if get_variable_type(&res).contains("Namespace") {
-        Ok(namespace_id.clone())
-    } else {
-        Err("Could not add subscriber".to_string())
-    }   
+#[ic_cdk_macros::update]
+fn namespace_update(namespace_id: String, update: NamespaceUpdate) -> Result<String, RegistryError> {
+    audited("namespace_update", AuditEntity::Namespace, namespace_id.clone(), || {
+        let mut ns = match namespace(namespace_id.clone()) {
+            Some(ns) => ns,
+            None => return Err("The namespace was not found".to_string().into()),
+        };
+
+        let mut errors = Vec::new();
+        if let Some(name) = &update.name {
+            check_name(&mut errors, "name", name);
+        }
+        if let Some(description) = &update.description {
+            check_description(&mut errors, "description", description);
+        }
+        validation_result(errors)?;
+
+        version_check(ns.version, update.expected_version)?;
+
+        let old_name = ns.name.clone();
+
+        if let Some(name) = update.name {
+            if namespace_id_by_name(&name).is_some_and(|id| id != namespace_id) {
+                return Err("A namespace with this name already exists".to_string().into());
+            }
+            ns.name = name;
+        }
+
+        if let Some(description) = update.description {
+            ns.description = description;
+        }
+
+        let deactivated = ns.active && update.active == Some(false);
+
+        if let Some(active) = update.active {
+            ns.active = active;
+        }
+
+        ns.version = version_next(ns.version);
+        ns.updated_at = Some(ic_cdk::api::time());
+
+        let name = ns.name.clone();
+        MAP_NAMESPACE.with(|p| p.borrow_mut().insert(namespace_id.clone(), ns));
+        namespace_name_index_move(&old_name, &name, &namespace_id);
+
+        // Subscribers without another active namespace stay where they are
+        if deactivated {
+            namespace_evacuate(&namespace_id, None);
+        }
+
+        Ok(namespace_id)
+    })
+}
+
+#[ic_cdk_macros::update]
+fn namespace_register_subscriber(namespace_id: String, subscriber_id: String) -> Result<String, String> { 
+    audited("namespace_register_subscriber", AuditEntity::Subscriber, subscriber_id.clone(), || {
+        subscriber_assign_namespace(&subscriber_id, &namespace_id)?;
+        Ok(namespace_id)
+    })
 }
 
 #[ic_cdk_macros::query]
@@ -141,23 +373,25 @@ fn namespace(namespace_id: String) -> Option<Namespaces> {
 }
 
 #[ic_cdk_macros::query]
-fn namespaces() -> Vec<Namespaces> {
-    let mut namespaces: Vec<Namespaces> = Vec::new();
+fn namespaces(args: Option<ListArgs>) -> Page<Namespaces> {
+    let args = args.unwrap_or_default();
 
     MAP_NAMESPACE.with(|p| {
-        for (k, v) in p.borrow().iter() {
-            let nspace = Namespaces {
+        paginate(
+            p.borrow().range((page_start(&args), Bound::Unbounded)),
+            &args,
+            |_, v| active_matches(v.active, &args) && name_matches(&v.name, &args),
+            |k, v| Namespaces {
                 id: k,
                 name: v.name,
                 description: v.description,
                 subscribers: v.subscribers,
                 active: v.active,
-            };
-
-            namespaces.push(nspace);
-        }
-    }); 
-    namespaces   
+                version: v.version,
+                updated_at: v.updated_at,
+            },
+        )
+    })
 }
 
 #[ic_cdk_macros::query]
@@ -182,6 +416,8 @@ fn namespaces_by_topic(topic_id: String) -> Vec<Namespaces> {
                         description: v.description,
                         subscribers: v.subscribers,
                         active: v.active,
+                        version: v.version,
+                        updated_at: v.updated_at,
                     };
     
                     namespaces.push(ns);
@@ -204,6 +440,8 @@ fn namespace_by_subscriber(subscriber_id: String) -> Namespaces {
             description: ns.description,
             subscribers: ns.subscribers,
             active: ns.active,
+            version: ns.version,
+            updated_at: ns.updated_at,
         }
 }
 
@@ -213,6 +451,11 @@ fn namespace_by_subscriber(subscriber_id: String) -> Namespaces {
 //
 //  topic_register      Register topic
 //  topic_unregister    Remove topic
+//  topic_rename        Rename topic
+//  topic_update        Update topic details
+//  topic_rebalance     Spread subscribers evenly over the topic's namespaces
+//  topic_add_namespace     Attach a namespace to a topic
+//  topic_remove_namespace  Detach a namespace from a topic
 //  topic               Lookup topic
 //  topics              Get all topics
 //  topic_by_name       Get topic by name
@@ -220,31 +463,160 @@ fn namespace_by_subscriber(subscriber_id: String) -> Namespaces {
 /******************************************************/
 
 #[ic_cdk_macros::update]
-fn topic_register(topic: Topic) -> Result<String, String> {
-    let id = create_uuid();
-    let res = MAP_TOPIC.with(|p| p.borrow_mut().insert(id.clone(), topic));
+fn topic_register(mut topic: Topic) -> Result<String, RegistryError> {
+    audited_create("topic_register", AuditEntity::Topic, || {
+        let mut errors = Vec::new();
+        check_name(&mut errors, "name", &topic.name);
+        check_description(&mut errors, "description", &topic.description);
+        check_list_len(&mut errors, "namespaces", topic.namespaces.len(), MAX_TOPIC_NAMESPACES);
+        validation_result(errors)?;
+
+        if topic_id_by_name(&topic.name).is_some() {
+            return Err("A topic with this name already exists".to_string().into());
+        }
 
-    if get_variable_type(&res).contains("Topic") {
-        Ok(id.to_string())
-    } else {
-        Err("Could not register the topic".to_string())
-    }   
+        for namespace_id in topic.namespaces.iter() {
+            if !MAP_NAMESPACE.with(|p| p.borrow().contains_key(namespace_id)) {
+                return Err(format!("The namespace {} was not found", namespace_id).into());
+            }
+        }
+
+        topic.version = Some(1);
+        topic.updated_at = Some(ic_cdk::api::time());
+
+        let id = create_uuid();
+        let name = topic.name.clone();
+        let res = MAP_TOPIC.with(|p| p.borrow_mut().insert(id.clone(), topic));
+
+        if get_variable_type(&res).contains("Topic") {
+            topic_name_index_set(&name, &id);
+            Ok(id.to_string())
+        } else {
+            Err("Could not register the topic".to_string().into())
+        }   
+    })
 }
 
 #[ic_cdk_macros::update]
-fn topic_unregister(topic_id: String) -> Result<String, String>  {
-    
-    if !MAP_TOPIC.with(|p| p.borrow().contains_key(&topic_id)) {
-        return Err("The topic was not found".to_string());
-    }
+fn topic_unregister(topic_id: String, mode: Option<DeleteMode>) -> Result<String, String>  {
+    audited("topic_unregister", AuditEntity::Topic, topic_id.clone(), || {
+        if !MAP_TOPIC.with(|p| p.borrow().contains_key(&topic_id)) {
+            return Err("The topic was not found".to_string());
+        }
 
-    let res = MAP_TOPIC.with(|p| {p.borrow_mut().remove(&topic_id)});
+        let subscriber_ids = subscriber_ids_by_topic(&topic_id);
 
-    if get_variable_type(&res).contains("Topic") {
-        Ok(topic_id.to_string())
-    } else {
-        Err("Could not unregister topic".to_string())
-    }   
+        match mode.unwrap_or(DeleteMode::Restrict) {
+            DeleteMode::Restrict => {
+                if !subscriber_ids.is_empty() {
+                    return Err(format!("The topic still has {} subscriber(s)", subscriber_ids.len()));
+                }
+            }
+            DeleteMode::Cascade => {
+                for subscriber_id in subscriber_ids.iter() {
+                    subscriber_delete_audited("topic_unregister", subscriber_id);
+                }
+            }
+        }
+
+        let res = MAP_TOPIC.with(|p| {p.borrow_mut().remove(&topic_id)});
+
+        if let Some(topic) = res {
+            if topic_id_by_name(&topic.name) == Some(topic_id.clone()) {
+                topic_name_index_remove(&topic.name);
+            }
+            Ok(topic_id.to_string())
+        } else {
+            Err("Could not unregister topic".to_string())
+        }   
+    })
+}
+
+#[ic_cdk_macros::update]
+fn topic_rename(topic_id: String, name: String) -> Result<String, RegistryError> {
+    audited("topic_rename", AuditEntity::Topic, topic_id.clone(), || {
+        let topic = match MAP_TOPIC.with(|p| p.borrow().get(&topic_id)) {
+            Some(topic) => topic,
+            None => return Err("The topic was not found".to_string().into()),
+        };
+
+        topic_update(topic_id, TopicUpdate {
+            name: Some(name),
+            description: None,
+            active: None,
+            expected_version: topic.version.unwrap_or(0),
+        })
+    })
+}
+
+#[ic_cdk_macros::update]
+fn topic_update(topic_id: String, update: TopicUpdate) -> Result<String, RegistryError> {
+    audited("topic_update", AuditEntity::Topic, topic_id.clone(), || {
+        let mut topic = match MAP_TOPIC.with(|p| p.borrow().get(&topic_id)) {
+            Some(topic) => topic,
+            None => return Err("The topic was not found".to_string().into()),
+        };
+
+        let mut errors = Vec::new();
+        if let Some(name) = &update.name {
+            check_name(&mut errors, "name", name);
+        }
+        if let Some(description) = &update.description {
+            check_description(&mut errors, "description", description);
+        }
+        validation_result(errors)?;
+
+        version_check(topic.version, update.expected_version)?;
+
+        let old_name = topic.name.clone();
+
+        if let Some(name) = update.name {
+            if topic_id_by_name(&name).is_some_and(|id| id != topic_id) {
+                return Err("A topic with this name already exists".to_string().into());
+            }
+            topic.name = name;
+        }
+
+        if let Some(description) = update.description {
+            topic.description = description;
+        }
+
+        if let Some(active) = update.active {
+            topic.active = active;
+        }
+
+        topic.version = version_next(topic.version);
+        topic.updated_at = Some(ic_cdk::api::time());
+
+        let name = topic.name.clone();
+        MAP_TOPIC.with(|p| p.borrow_mut().insert(topic_id.clone(), topic));
+        topic_name_index_move(&old_name, &name, &topic_id);
+
+        Ok(topic_id)
+    })
+}
+
+#[ic_cdk_macros::update]
+fn topic_rebalance(topic_id: String) -> Result<u64, String> {
+    audited("topic_rebalance", AuditEntity::Topic, topic_id.clone(), || {
+        topic_subscribers_rebalance(&topic_id)
+    })
+}
+
+#[ic_cdk_macros::update]
+fn topic_add_namespace(topic_id: String, namespace_id: String) -> Result<String, String> {
+    audited("topic_add_namespace", AuditEntity::Topic, topic_id.clone(), || {
+        topic_namespace_add(&topic_id, &namespace_id)?;
+        Ok(topic_id)
+    })
+}
+
+#[ic_cdk_macros::update]
+fn topic_remove_namespace(topic_id: String, namespace_id: String) -> Result<String, String> {
+    audited("topic_remove_namespace", AuditEntity::Topic, topic_id.clone(), || {
+        topic_namespace_remove(&topic_id, &namespace_id)?;
+        Ok(topic_id)
+    })
 }
 
 #[ic_cdk_macros::query]
@@ -257,59 +629,48 @@ fn topic(topic_id: String) -> Topics {
         description: topic.description,
         namespaces: topic.namespaces,
         active: topic.active,
+        version: topic.version,
+        updated_at: topic.updated_at,
     }
 }
 
 #[ic_cdk_macros::query]
-fn topics() -> Vec<Topics> {
-    let mut topics: Vec<Topics> = Vec::new();
+fn topics(args: Option<ListArgs>) -> Page<Topics> {
+    let args = args.unwrap_or_default();
 
     MAP_TOPIC.with(|p| {
-        for (k, v) in p.borrow().iter() {
-            let tpics = Topics {
+        paginate(
+            p.borrow().range((page_start(&args), Bound::Unbounded)),
+            &args,
+            |_, v| active_matches(v.active, &args) && name_matches(&v.name, &args),
+            |k, v| Topics {
                 id: k,
                 name: v.name,
                 description: v.description,
                 namespaces: v.namespaces,
                 active: v.active,
-            };
-
-            topics.push(tpics);
-        }
-    }); 
-    topics   
+                version: v.version,
+                updated_at: v.updated_at,
+            },
+        )
+    })
 }
 
 #[ic_cdk_macros::query]
 fn topic_by_name(topic_name: String) -> Topics {
 
-    let mut topic: Topics = Topics {
-        id: "".to_string(), 
-        name: "".to_string(), 
-        description: "".to_string(), 
-        namespaces: Vec::new(), 
-        active: true
-    };
-
-    MAP_TOPIC.with(|p| {
-        for (k, v) in p.borrow().iter() {
-
-            if v.name == topic_name {
-                topic = Topics {
-                    id: k,
-                    name: v.name,
-                    description: v.description,
-                    namespaces: v.namespaces,
-                    active: v.active,
-                };
-            }
-        }
-    }); 
-
-    ic_cdk::print(topic.name.to_string());
-    //ic_cdk::print(v.name.to_string());
-
-    topic   
+    match topic_id_by_name(&topic_name) {
+        Some(topic_id) => topic(topic_id),
+        None => Topics {
+            id: "".to_string(), 
+            name: "".to_string(), 
+            description: "".to_string(), 
+            namespaces: Vec::new(), 
+            active: true,
+            version: None,
+            updated_at: None,
+        },
+    }
 }
 
 
@@ -319,19 +680,25 @@ fn topic_by_name(topic_name: String) -> Topics {
 //
 //  subscriber_register         Register subscription
 //  subscriber_unregister       Remove subscription
+//  subscriber_update           Update subscription details
 //  subscriber                  Lookup subscriber
 //  subscribers                 Get all subscribers
 //  subscribers_by_topic        Get all topic subscribers
 //  subscribers_by_topic_name   Get all topic subscribers
+//  subscribers_by_namespace    Get all namespace subscribers
 //
 /******************************************************/
 
 
 #[ic_cdk_macros::update]
-pub async fn subscriber_register(subscriber: Subscribers) -> Result<String, String> {
+pub async fn subscriber_register(subscriber: Subscribers) -> Result<String, RegistryError> {
+    audited_create("subscriber_register", AuditEntity::Subscriber, || subscriber_create(subscriber))
+}
+
+fn subscriber_create(subscriber: Subscribers) -> Result<String, RegistryError> {
 
     let mut _id: String = subscriber.id;
-    let mut _canister_id: String = subscriber.canister_id;
+    let mut _canister_id: Principal = subscriber.canister_id;
     let mut _callback: String = subscriber.callback;
     let mut _name: String = subscriber.name;
     let mut _description: String = subscriber.description;
@@ -339,39 +706,122 @@ pub async fn subscriber_register(subscriber: Subscribers) -> Result<String, Stri
     let mut _namespace: String = subscriber.namespace;
     let mut _active: bool = subscriber.active;
 
-    let mut id = create_uuid();
+    let mut errors = Vec::new();
+    if !_id.is_empty() {
+        check_id(&mut errors, "id", &_id);
+    }
+    check_principal(&mut errors, "canister_id", &_canister_id);
+    check_callback(&mut errors, "callback", &_callback);
+    check_label(&mut errors, "name", &_name);
+    check_description(&mut errors, "description", &_description);
+    check_id(&mut errors, "topic", &_topic);
+    check_id(&mut errors, "namespace", &_namespace);
+    validation_result(errors)?;
 
     if _id == "".to_string() {
-        _id = id.clone();
+        _id = create_uuid();
+    } else if MAP_SUBSCRIBER.with(|p| p.borrow().contains_key(&_id)) {
+        return Err("A subscriber with this id already exists".to_string().into());
+    }
+
+    if !MAP_TOPIC.with(|p| p.borrow().contains_key(&_topic)) {
+        return Err("The topic was not found".to_string().into());
+    }
+
+    if !MAP_NAMESPACE.with(|p| p.borrow().contains_key(&_namespace)) {
+        return Err("The namespace was not found".to_string().into());
+    }
+
+    if !namespace_in_topic(&_topic, &_namespace) {
+        return Err("The namespace is not assigned to the topic".to_string().into());
     }
  
-    let result = MAP_SUBSCRIBER.with(|p| p.borrow_mut().insert(_id.clone().to_string(), Subscribers {
+    let result = subscriber_insert(&_id, Subscribers {
         id: _id.clone(),
-        canister_id: _canister_id.clone(),
+        canister_id: _canister_id,
         callback: _callback.clone(),
         name: _name.clone(),
         description: _description.clone(),
         topic: _topic.clone(),
         namespace: _namespace.clone(),
         active: true,
-    }));
+        version: Some(1),
+        updated_at: Some(ic_cdk::api::time()),
+    });
 
     if get_variable_type(&result).contains("Subscribers") {
+        namespace_subscriber_add(&_namespace, &_id);
         Ok(_id.clone().to_string())
     } else {
-        Err("Could not unregister topic".to_string())
+        Err("Could not unregister topic".to_string().into())
     }   
 }
 
 #[ic_cdk_macros::update]
 fn subscriber_unregister(subscriber_id: String) -> Result<String, String> {
-    let res = MAP_SUBSCRIBER.with(|p| {p.borrow_mut().remove(&subscriber_id)});
+    audited("subscriber_unregister", AuditEntity::Subscriber, subscriber_id.clone(), || {
+        let res = subscriber_delete(&subscriber_id);
 
-    if get_variable_type(&res).contains("Subscribers") {
-        Ok(subscriber_id.to_string())
-    } else {
-        Err("Could not unregister subscriber".to_string())
-    }   
+        if res.is_some() {
+            Ok(subscriber_id.to_string())
+        } else {
+            Err("Could not unregister subscriber".to_string())
+        }   
+    })
+}
+
+#[ic_cdk_macros::update]
+fn subscriber_update(subscriber_id: String, update: SubscriberUpdate) -> Result<String, RegistryError> {
+    audited("subscriber_update", AuditEntity::Subscriber, subscriber_id.clone(), || {
+        let mut subscriber = match MAP_SUBSCRIBER.with(|p| p.borrow().get(&subscriber_id)) {
+            Some(subscriber) => subscriber,
+            None => return Err("The subscriber was not found".to_string().into()),
+        };
+
+        let mut errors = Vec::new();
+        if let Some(canister_id) = &update.canister_id {
+            check_principal(&mut errors, "canister_id", canister_id);
+        }
+        if let Some(callback) = &update.callback {
+            check_callback(&mut errors, "callback", callback);
+        }
+        if let Some(name) = &update.name {
+            check_label(&mut errors, "name", name);
+        }
+        if let Some(description) = &update.description {
+            check_description(&mut errors, "description", description);
+        }
+        validation_result(errors)?;
+
+        version_check(subscriber.version, update.expected_version)?;
+
+        if let Some(canister_id) = update.canister_id {
+            subscriber.canister_id = canister_id;
+        }
+
+        if let Some(callback) = update.callback {
+            subscriber.callback = callback;
+        }
+
+        if let Some(name) = update.name {
+            subscriber.name = name;
+        }
+
+        if let Some(description) = update.description {
+            subscriber.description = description;
+        }
+
+        if let Some(active) = update.active {
+            subscriber.active = active;
+        }
+
+        subscriber.version = version_next(subscriber.version);
+        subscriber.updated_at = Some(ic_cdk::api::time());
+
+        subscriber_insert(&subscriber_id, subscriber);
+
+        Ok(subscriber_id)
+    })
 }
 
 #[ic_cdk_macros::query]
@@ -387,73 +837,66 @@ fn subscriber(subscriber_id: String) -> Subscribers {
         topic: subscriber.topic,
         namespace: subscriber.namespace,
         active: subscriber.active,
+        version: subscriber.version,
+        updated_at: subscriber.updated_at,
     }
 }
 
 #[ic_cdk_macros::query]
-fn subscribers() -> Vec<Subscribers> {
-    let mut subscribers: Vec<Subscribers> = Vec::new();
+fn subscribers(args: Option<ListArgs>) -> Page<Subscribers> {
+    let args = args.unwrap_or_default();
 
-    MAP_SUBSCRIBER.with(|p| {
-        for (k, v) in p.borrow().iter() {
-            let subs = Subscribers {
-                id: k,
-                name: v.name,
-                description: v.description,
-                callback: v.callback,
-                canister_id: v.canister_id,
-                topic: v.topic,
-                namespace: v.namespace,
-                active: v.active,
-            };
+    let keep = |_: &String, v: &Subscribers| {
+        active_matches(v.active, &args) && name_matches(&v.name, &args)
+    };
+    let to_item = |k: String, v: Subscribers| Subscribers {
+        id: k,
+        name: v.name,
+        description: v.description,
+        callback: v.callback,
+        canister_id: v.canister_id,
+        topic: v.topic,
+        namespace: v.namespace,
+        active: v.active,
+        version: v.version,
+        updated_at: v.updated_at,
+    };
 
-            subscribers.push(subs);
-        };
-    }); 
-    subscribers   
+    match &args.topic {
+        Some(topic_id) => subscribers_page_by_topic(topic_id, &args, keep, to_item),
+        None => MAP_SUBSCRIBER.with(|p| {
+            paginate(p.borrow().range((page_start(&args), Bound::Unbounded)), &args, keep, to_item)
+        }),
+    }
 }
 
 #[ic_cdk_macros::query]
 fn subscribers_by_topic(topic: String) -> Vec<Subscribers> {
-    let mut subscribers: Vec<Subscribers> = Vec::new();
-    
-    MAP_SUBSCRIBER.with(|p| {
-        for (k, v) in p.borrow().iter() {
-
-            if v.topic == topic {
-                let subs = Subscribers {
-                    id: k,
-                    name: v.name,
-                    description: v.description,
-                    callback: v.callback,
-                    canister_id: v.canister_id,
-                    topic: v.topic,
-                    namespace: v.namespace,
-                    active: v.active,
-                };
-
-                subscribers.push(subs);
-            }
-        };
-    }); 
-    subscribers   
+    subscribers_by_ids(subscriber_ids_by_topic(&topic))
 }
 
 #[ic_cdk_macros::query]
 fn subscribers_by_topic_name(topic_name: String) -> Vec<Subscribers> {
-    let mut subscribers: Vec<Subscribers> = Vec::new();
+    match topic_id_by_name(&topic_name) {
+        Some(topic_id) => subscribers_by_ids(subscriber_ids_by_topic(&topic_id)),
+        None => Vec::new(),
+    }
+}
+
+#[ic_cdk_macros::query]
+fn subscribers_by_namespace(namespace_id: String) -> Vec<Subscribers> {
+    subscribers_by_ids(subscriber_ids_by_namespace(&namespace_id))
+}
 
-    let topic_id = topic_by_name(topic_name).id; 
+fn subscribers_by_ids(ids: Vec<String>) -> Vec<Subscribers> {
+    let mut subscribers: Vec<Subscribers> = Vec::new();
 
-    ic_cdk::print(topic_id.clone());
-    
     MAP_SUBSCRIBER.with(|p| {
-        for (k, v) in p.borrow().iter() {
+        let map = p.borrow();
 
-            ic_cdk::print(v.clone().topic);
-
-            if v.topic == topic_id.clone() {
-                let subs = Subscribers {
+        for k in ids {
+            if let Some(v) = map.get(&k) {
+                subscribers.push(Subscribers {
                     id: k,
                     name: v.name,
                     description: v.description,
@@ -462,13 +905,13 @@ fn subscribers_by_topic_name(topic_name: String) -> Vec<Subscribers> {
                     topic: v.topic,
                     namespace: v.namespace,
                     active: v.active,
-                };
-
-                subscribers.push(subs);
+                    version: v.version,
+                    updated_at: v.updated_at,
+                });
             }
-        };
-    }); 
-    subscribers   
+        }
+    });
+    subscribers
 }
 
 
@@ -490,38 +933,34 @@ pub async fn agent_subscribe(topic_name: String, callback: String) -> CallString
     let subscriber_principal_id = ic_cdk::caller();
 
     let topic: Topics = topic_by_name(topic_name);
-    let mut min_subs_count = 1000000;
-    let mut namespace_id = String::new();
-
-    ic_cdk::print(topic.name.to_string());
 
-    for i in topic.namespaces.iter() {
-        ic_cdk::print(i.to_string());
-
-        let ns = namespace(i.to_string()).unwrap();
-        let size = ns.subscribers.len();
-
-        if size < min_subs_count {
-            min_subs_count = size;
-            namespace_id = i.to_string();
-        }
+    if topic.id.is_empty() {
+        ic_cdk::trap("The topic was not found");
     }
 
+    let namespace_id = match namespace_least_loaded(&topic.id) {
+        Some(namespace_id) => namespace_id,
+        None => ic_cdk::trap("The topic has no active namespaces"),
+    };
+
     let mut _id = create_uuid();
 
     let subscriber = Subscribers {
         id: _id.clone().to_string(),
-        canister_id: subscriber_principal_id.to_string(),
+        canister_id: subscriber_principal_id,
         callback: callback,
         name: "".to_string(),
         description: "".to_string(),
         topic: topic.id,
         namespace: namespace_id.clone(),
         active: true,
+        version: Some(1),
+        updated_at: Some(ic_cdk::api::time()),
     };
 
-    let sub = subscriber_register(subscriber).await;
-    let res = namespace_register_subscriber(namespace_id, _id.clone().to_string());
+    if let Err(err) = audited_create("agent_subscribe", AuditEntity::Subscriber, || subscriber_create(subscriber)) {
+        ic_cdk::trap(&err.to_string());
+    }
 
     CallStringResponse {
         result: _id.clone().to_string()
@@ -530,33 +969,12 @@ pub async fn agent_subscribe(topic_name: String, callback: String) -> CallString
 
 #[ic_cdk_macros::update]
 pub async fn agent_unsubscribe(subscription_id: String) -> Result<String, String> {
-    let subscriber_principal_id = ic_cdk::caller();
-    let mut namespace = namespace_by_subscriber(subscription_id.clone());
-
-    namespace.subscribers.retain(|x| x != &subscription_id.clone());
-
-    let mod_namespace = Namespaces {
-        id: namespace.id.clone(),
-        name: namespace.name,
-        description: namespace.description,
-        subscribers: namespace.subscribers,
-        active: namespace.active,
-    };
-
-    let res_namespace = MAP_NAMESPACE.with(|p| p.borrow_mut().insert(namespace.id.clone(), mod_namespace));
-
-    if get_variable_type(&res_namespace).contains("Namespace") {
-        let res_subscriber = MAP_SUBSCRIBER.with(|p| {p.borrow_mut().remove(&subscription_id)});
-
-        if get_variable_type(&res_subscriber).contains("Subscriber") {
-
-            Ok(subscription_id.to_string())
-        } else {
-            Err("Could not unregister subscription".to_string())
-        }   
-    } else {
-        Err("Could not unregister subscription".to_string())
-    }   
+    audited("agent_unsubscribe", AuditEntity::Subscriber, subscription_id.clone(), || {
+        match subscriber_delete(&subscription_id) {
+            Some(_) => Ok(subscription_id.to_string()),
+            None => Err("Could not unregister subscription".to_string()),
+        }
+    })
 }
 
 
@@ -582,30 +1000,8 @@ pub async fn agent_subscription(subscriber_id: String) -> CallSubscriberResponse
 pub async fn agent_subscriptions() -> CallSubscribersResponse { 
     let subscriber_principal_id = ic_cdk::caller();
 
-    let mut subscribers: Vec<Subscribers> = Vec::new();
-
-    MAP_SUBSCRIBER.with(|p| {
-        for (k, v) in p.borrow().iter() {
-
-            if v.canister_id == subscriber_principal_id.to_string() {
-                let subs = Subscribers {
-                    id: k,
-                    name: v.name,
-                    description: v.description,
-                    callback: v.callback,
-                    canister_id: v.canister_id,
-                    topic: v.topic,
-                    namespace: v.namespace,
-                    active: v.active,
-                };
-
-                subscribers.push(subs);
-            }
-        };
-    }); 
-
     CallSubscribersResponse {
-        result: subscribers
+        result: subscribers_by_ids(subscriber_ids_by_canister(&subscriber_principal_id.to_text()))
     }
 }
 
@@ -616,48 +1012,105 @@ pub async fn agent_subscriptions() -> CallSubscribersResponse {
 //
 //  canister_register               Add canister registry
 //  canister_unregister             Remove canister registry
+//  canister_update                 Update canister registry
 //  canister                        Get a specific canister's details
 //  canisters                       Get all canister
-//  canisters_remote_set            Set canister settings in 
-//                                  remote canisters
+//  canisters_remote_set            Push the canister settings to
+//                                  remote canisters, see config_push
 //  
 /******************************************************/
 
 #[ic_cdk_macros::update]
-pub fn canister_register(canister: Canisters) -> Result<String, String> {
-
-    let mut _id = create_uuid();
+pub fn canister_register(canister: Canisters) -> Result<String, RegistryError> {
+    audited("canister_register", AuditEntity::Canister, canister.canister_name.clone(), || {
+        let mut errors = Vec::new();
+        check_principal(&mut errors, "canister_id", &canister.canister_id);
+        check_name(&mut errors, "canister_name", &canister.canister_name);
+        check_label(&mut errors, "name", &canister.name);
+        check_description(&mut errors, "description", &canister.description);
+        validation_result(errors)?;
+
+        let mut _id = create_uuid();
+
+        let canister_insert = Canisters {
+            id: _id.clone().to_string(),
+            canister_id: canister.clone().canister_id,
+            canister_name: canister.clone().canister_name,
+            name: canister.clone().name,
+            description: canister.clone().description,
+            active: true,
+            version: Some(1),
+            updated_at: Some(ic_cdk::api::time()),
+        };
 
-    let canister_insert = Canisters {
-        id: _id.clone().to_string(),
-        canister_id: canister.clone().canister_id,
-        canister_name: canister.clone().canister_name,
-        name: canister.clone().name,
-        description: canister.clone().description,
-        active: true,
-    };
+        let result = MAP_CANISTER.with(|p| p.borrow_mut().insert(canister.clone().canister_name.to_string(), canister_insert));
 
-    let result = MAP_CANISTER.with(|p| p.borrow_mut().insert(canister.clone().canister_name.to_string(), canister_insert));
+        if get_variable_type(&result).contains("Canisters") {
+            Ok(_id.clone().to_string())
+        } else {
+            Err("Could not register canister".to_string().into())
+        }   
+    })
+}
 
-    if get_variable_type(&result).contains("Canisters") {
-        Ok(_id.clone().to_string())
-    } else {
-        Err("Could not register canister".to_string())
-    }   
+#[ic_cdk_macros::update]
+pub fn canister_unregister(canister_name: String) -> Result<String, String> {
+    audited("canister_unregister", AuditEntity::Canister, canister_name.clone(), || {
+        let res = MAP_CANISTER.with(|p| {p.borrow_mut().remove(&canister_name)});
 
+        if get_variable_type(&res).contains("Canisters") {
+            Ok(format!("Success: Canister {} has been removed", canister_name.to_string()).to_string())
+        } else {
+            Err("Could not unregister subscriber".to_string())
+        }
+    })
 }
 
 #[ic_cdk_macros::update]
-pub fn canister_unregister(canister_name: String) -> Result<String, String> {
+pub fn canister_update(canister_name: String, update: CanisterUpdate) -> Result<String, RegistryError> {
+    audited("canister_update", AuditEntity::Canister, canister_name.clone(), || {
+        let mut canister = match MAP_CANISTER.with(|p| p.borrow().get(&canister_name)) {
+            Some(canister) => canister,
+            None => return Err("The canister was not found".to_string().into()),
+        };
 
-    let res = MAP_CANISTER.with(|p| {p.borrow_mut().remove(&canister_name)});
+        let mut errors = Vec::new();
+        if let Some(canister_id) = &update.canister_id {
+            check_principal(&mut errors, "canister_id", canister_id);
+        }
+        if let Some(name) = &update.name {
+            check_label(&mut errors, "name", name);
+        }
+        if let Some(description) = &update.description {
+            check_description(&mut errors, "description", description);
+        }
+        validation_result(errors)?;
 
-    if get_variable_type(&res).contains("Canisters") {
-        Ok(format!("Success: Canister {} has been removed", canister_name.to_string()).to_string())
-    } else {
-        Err("Could not unregister subscriber".to_string())
-    }
+        version_check(canister.version, update.expected_version)?;
 
+        if let Some(canister_id) = update.canister_id {
+            canister.canister_id = canister_id;
+        }
+
+        if let Some(name) = update.name {
+            canister.name = name;
+        }
+
+        if let Some(description) = update.description {
+            canister.description = description;
+        }
+
+        if let Some(active) = update.active {
+            canister.active = active;
+        }
+
+        canister.version = version_next(canister.version);
+        canister.updated_at = Some(ic_cdk::api::time());
+
+        MAP_CANISTER.with(|p| p.borrow_mut().insert(canister_name.clone(), canister));
+
+        Ok(canister_name)
+    })
 }
 
 #[ic_cdk_macros::query]
@@ -668,44 +1121,25 @@ pub fn canister(canister_name: String) -> Option<Canisters> {
 
 
 #[ic_cdk_macros::query]
-pub fn canisters() -> Vec<Canisters> {
-
-    let mut canisters: Vec<Canisters> = Vec::new();
+pub fn canisters(args: Option<ListArgs>) -> Page<Canisters> {
+    let args = args.unwrap_or_default();
 
     MAP_CANISTER.with(|p| {
-        for (k, v) in p.borrow().iter() {
-            let cans = Canisters {
-                id: v.id,
-                canister_id: v.canister_id,
-                canister_name: v.canister_name,
-                name: v.name,
-                description: v.description,
-                active: v.active,
-            };
-
-            canisters.push(cans);
-        }
-    }); 
-
-    canisters
+        paginate(
+            p.borrow().range((page_start(&args), Bound::Unbounded)),
+            &args,
+            |_, v| active_matches(v.active, &args) && name_matches(&v.canister_name, &args),
+            |_, v| v,
+        )
+    })
 }
 
 
-#[ic_cdk_macros::update]
-pub async fn canisters_remote_set() -> () {
-    let canisters = canisters();
-
-    for intercanisters in canisters.iter() {
-
-        if intercanisters.clone().canister_name.to_string() != "registry_backend".to_string() {
-            for canister in canisters.iter() {
-                let _: () = ic_cdk::call(Principal::from_text(intercanisters.canister_id.to_string()).expect("Could not decode the principal."), "canister_settings_store", (canister.canister_name.to_string(), canister.canister_id.to_string())).await.unwrap();
-    
-                ic_cdk::print(format!("{} - {}", intercanisters.canister_name.to_string(), canister.canister_id.to_string()));
-    
-            }
-        }       
-    }    
+/// Kept for existing deployment scripts. Pushes go through the config
+/// distribution, one document per canister.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+pub fn canisters_remote_set() {
+    config_push();
 }
 
 /*
@@ -0,0 +1,71 @@
+use ic_stable_structures::{
+    DefaultMemoryImpl,
+    Memory as _,
+    memory_manager::MemoryId,
+    memory_manager::MemoryManager,
+    memory_manager::VirtualMemory,
+};
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+
+/******************************************************/
+//
+//  MEMORY
+//
+//  All stable structures get their memory from the one
+//  MemoryManager below. IDs 0-3 belong to the legacy
+//  layout, where every structure created its own manager
+//  over the same stable memory. They are only read by the
+//  layout migration and must not be reused.
+//
+//  memory_get          Virtual memory for a MemoryId
+//  memory_in_use       Check if a MemoryId was ever written
+//
+/******************************************************/
+
+// LEGACY ///////////////////////////////////////////
+
+pub const LEGACY_MEMORY_TOPIC: MemoryId = MemoryId::new(0);
+pub const LEGACY_MEMORY_NAMESPACE: MemoryId = MemoryId::new(1);
+pub const LEGACY_MEMORY_SUBSCRIBER: MemoryId = MemoryId::new(2);
+pub const LEGACY_MEMORY_CANISTER: MemoryId = MemoryId::new(3);
+
+/// Every ID of the legacy layout.
+pub const LEGACY_MEMORY_IDS: [MemoryId; 4] = [
+    MemoryId::new(0), MemoryId::new(1), MemoryId::new(2), MemoryId::new(3),
+];
+
+// CURRENT ///////////////////////////////////////////
+
+pub const MEMORY_TOPIC_NAME: MemoryId = MemoryId::new(4);
+pub const MEMORY_NAMESPACE_NAME: MemoryId = MemoryId::new(5);
+pub const MEMORY_SUBSCRIBER_BY_TOPIC: MemoryId = MemoryId::new(6);
+pub const MEMORY_SUBSCRIBER_BY_NAMESPACE: MemoryId = MemoryId::new(7);
+pub const MEMORY_SUBSCRIBER_BY_CANISTER: MemoryId = MemoryId::new(8);
+pub const MEMORY_SCHEMA_VERSION: MemoryId = MemoryId::new(9);
+pub const MEMORY_TOPIC: MemoryId = MemoryId::new(10);
+pub const MEMORY_NAMESPACE: MemoryId = MemoryId::new(11);
+pub const MEMORY_SUBSCRIBER: MemoryId = MemoryId::new(12);
+pub const MEMORY_CANISTER: MemoryId = MemoryId::new(13);
+pub const MEMORY_AUDIT_LOG: MemoryId = MemoryId::new(14);
+pub const MEMORY_AUDIT_BY_ENTITY: MemoryId = MemoryId::new(15);
+pub const MEMORY_AUDIT_BY_CALLER: MemoryId = MemoryId::new(16);
+pub const MEMORY_CONFIG: MemoryId = MemoryId::new(17);
+pub const MEMORY_CONFIG_TARGET: MemoryId = MemoryId::new(18);
+pub const MEMORY_SUBSCRIBER_CIRCUIT: MemoryId = MemoryId::new(19);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn memory_get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// Checking the size does not allocate, unlike initializing a structure.
+pub fn memory_in_use(id: MemoryId) -> bool {
+    memory_get(id).size() > 0
+}
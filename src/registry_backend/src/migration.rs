@@ -0,0 +1,191 @@
+use candid::Principal;
+use ic_stable_structures::{StableBTreeMap, Storable, memory_manager::MemoryId};
+use crate::index::{subscriber_insert, subscriber_indexes_rebuild, name_indexes_rebuild};
+use crate::memory::{
+    memory_get, memory_in_use, Memory,
+    LEGACY_MEMORY_IDS, LEGACY_MEMORY_TOPIC, LEGACY_MEMORY_NAMESPACE,
+    LEGACY_MEMORY_SUBSCRIBER, LEGACY_MEMORY_CANISTER,
+    MEMORY_SCHEMA_VERSION,
+};
+use crate::types::{Canisters, Namespaces, Subscribers, Topic};
+use crate::relations::subscriber_delete;
+use crate::{MAP_TOPIC, MAP_NAMESPACE, MAP_SUBSCRIBER, MAP_CANISTER, SCHEMA_VERSION};
+
+const SCHEMA_PRINCIPAL_IDS: u64 = 1;
+const SCHEMA_VERSIONED_RECORDS: u64 = 2;
+const SCHEMA_CURRENT: u64 = SCHEMA_VERSIONED_RECORDS;
+
+
+/******************************************************/
+//
+//  MIGRATION
+//
+//  schema_version_init     Mark a fresh install as current
+//  layout_migrate          Copy data from the legacy memory layout
+//  migrations_run          Apply the migrations newer than the
+//                          stored schema version
+//  canister_ids_migrate    Re-encode text canister IDs as principals
+//  records_rewrite         Store every record at its current version
+//
+/******************************************************/
+
+fn schema_version() -> u64 {
+    SCHEMA_VERSION.with(|p| *p.borrow().get())
+}
+
+fn schema_version_set(version: u64) {
+    SCHEMA_VERSION.with(|p| p.borrow_mut().set(version)).expect("Could not store the schema version");
+}
+
+pub fn schema_version_init() {
+    schema_version_set(SCHEMA_CURRENT);
+}
+
+/// Copies the records written under the legacy memory layout to the current
+/// memory IDs. Everything is read before the first current structure is
+/// created.
+///
+/// The legacy managers each kept a private copy of the bucket table and only
+/// the last one written survives. Records can only be read from the memory
+/// IDs it lists; a map whose buckets it lost cannot be recovered. Which IDs
+/// were listed is logged.
+pub fn layout_migrate() {
+    if memory_in_use(MEMORY_SCHEMA_VERSION) || !LEGACY_MEMORY_IDS.iter().any(|id| memory_in_use(*id)) {
+        return;
+    }
+
+    let topics: Vec<(String, Topic)> = legacy_entries(LEGACY_MEMORY_TOPIC);
+    let namespaces: Vec<(String, Namespaces)> = legacy_entries(LEGACY_MEMORY_NAMESPACE);
+    let subscribers: Vec<(String, Subscribers)> = legacy_entries(LEGACY_MEMORY_SUBSCRIBER);
+    let canisters: Vec<(String, Canisters)> = legacy_entries(LEGACY_MEMORY_CANISTER);
+
+    let listed: Vec<String> = LEGACY_MEMORY_IDS.iter()
+        .map(|id| format!("{:?}={}", id, if memory_in_use(*id) { "listed" } else { "empty" }))
+        .collect();
+    ic_cdk::print(format!("Legacy memory IDs: {}", listed.join(" ")));
+
+    ic_cdk::print(format!(
+        "Migrating {} topics, {} namespaces, {} subscribers and {} canisters from the legacy memory layout",
+        topics.len(), namespaces.len(), subscribers.len(), canisters.len(),
+    ));
+
+    MAP_TOPIC.with(|p| {
+        let mut map = p.borrow_mut();
+
+        for (topic_id, topic) in topics {
+            map.insert(topic_id, topic);
+        }
+    });
+
+    MAP_NAMESPACE.with(|p| {
+        let mut map = p.borrow_mut();
+
+        for (namespace_id, ns) in namespaces {
+            map.insert(namespace_id, ns);
+        }
+    });
+
+    for (subscriber_id, subscriber) in subscribers {
+        subscriber_insert(&subscriber_id, subscriber);
+    }
+
+    MAP_CANISTER.with(|p| {
+        let mut map = p.borrow_mut();
+
+        for (canister_name, canister) in canisters {
+            map.insert(canister_name, canister);
+        }
+    });
+
+    name_indexes_rebuild();
+
+    // The legacy layout predates the schema version, every migration applies
+    schema_version_set(0);
+}
+
+fn legacy_entries<K, V>(id: MemoryId) -> Vec<(K, V)>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    if !memory_in_use(id) {
+        return Vec::new();
+    }
+
+    StableBTreeMap::<K, V, Memory>::load(memory_get(id)).iter().collect()
+}
+
+pub fn migrations_run() {
+    if schema_version() < SCHEMA_PRINCIPAL_IDS {
+        canister_ids_migrate();
+        schema_version_set(SCHEMA_PRINCIPAL_IDS);
+    }
+
+    if schema_version() < SCHEMA_VERSIONED_RECORDS {
+        records_rewrite();
+        schema_version_set(SCHEMA_VERSIONED_RECORDS);
+    }
+}
+
+/// Records written with text canister IDs decode through their legacy
+/// encoding; writing them back stores the principal form. Unparsable IDs
+/// decode to the anonymous principal and those records are dropped.
+fn canister_ids_migrate() {
+    let subscribers: Vec<_> = MAP_SUBSCRIBER.with(|p| p.borrow().iter().collect());
+
+    for (subscriber_id, subscriber) in subscribers {
+        if subscriber.canister_id == Principal::anonymous() {
+            ic_cdk::print(format!("Removing subscriber {} with an invalid canister id", subscriber_id));
+            subscriber_delete(&subscriber_id);
+        } else {
+            subscriber_insert(&subscriber_id, subscriber);
+        }
+    }
+
+    let canisters: Vec<_> = MAP_CANISTER.with(|p| p.borrow().iter().collect());
+
+    for (canister_name, canister) in canisters {
+        MAP_CANISTER.with(|p| {
+            let mut map = p.borrow_mut();
+
+            if canister.canister_id == Principal::anonymous() {
+                ic_cdk::print(format!("Removing canister {} with an invalid canister id", canister_name));
+                map.remove(&canister_name);
+            } else {
+                map.insert(canister_name, canister);
+            }
+        });
+    }
+
+    // The canister index was keyed by the raw text
+    subscriber_indexes_rebuild();
+}
+
+/// Older records are upgraded when they are read, so this is not required
+/// for correctness; it moves every record to the current encoding so that
+/// later builds only need to read the versions they were released with.
+fn records_rewrite() {
+    let topics: Vec<_> = MAP_TOPIC.with(|p| p.borrow().iter().collect());
+
+    for (topic_id, topic) in topics {
+        MAP_TOPIC.with(|p| p.borrow_mut().insert(topic_id, topic));
+    }
+
+    let namespaces: Vec<_> = MAP_NAMESPACE.with(|p| p.borrow().iter().collect());
+
+    for (namespace_id, ns) in namespaces {
+        MAP_NAMESPACE.with(|p| p.borrow_mut().insert(namespace_id, ns));
+    }
+
+    let subscribers: Vec<_> = MAP_SUBSCRIBER.with(|p| p.borrow().iter().collect());
+
+    for (subscriber_id, subscriber) in subscribers {
+        subscriber_insert(&subscriber_id, subscriber);
+    }
+
+    let canisters: Vec<_> = MAP_CANISTER.with(|p| p.borrow().iter().collect());
+
+    for (canister_name, canister) in canisters {
+        MAP_CANISTER.with(|p| p.borrow_mut().insert(canister_name, canister));
+    }
+}
@@ -0,0 +1,320 @@
+use std::{cmp::Reverse, collections::BTreeMap};
+use crate::index::{
+    subscriber_insert, subscriber_remove,
+    subscriber_ids_by_topic, subscriber_ids_by_namespace,
+};
+use crate::types::Subscribers;
+use crate::utils::version_next;
+use crate::{MAP_TOPIC, MAP_NAMESPACE, MAP_SUBSCRIBER, MAP_SUBSCRIBER_CIRCUIT};
+
+
+/******************************************************/
+//
+//  RELATIONS
+//
+//  Keeps both sides of the topic, namespace and subscriber
+//  relationships consistent
+//
+//  namespace_subscriber_add        Add a subscriber ID to a namespace
+//  namespace_subscriber_remove     Remove a subscriber ID from a namespace
+//  subscriber_assign_namespace     Move a subscriber to another namespace
+//  subscriber_delete               Remove a subscriber, its namespace entry and circuit
+//  topic_ids_by_namespace          Topics that list a namespace
+//  namespace_detach                Remove a namespace from all topics
+//  namespace_in_topic              Check if a topic lists a namespace
+//  topic_namespace_add             Attach a namespace to a topic
+//  topic_namespace_remove          Detach a namespace from a topic
+//  namespace_least_loaded          Active namespace of a topic with the fewest subscribers
+//  namespace_evacuate              Move a namespace's subscribers elsewhere
+//  topic_subscribers_rebalance     Spread a topic's subscribers evenly
+//
+//  The load of a namespace is always counted per topic: the
+//  subscribers of that topic assigned to the namespace.
+//
+/******************************************************/
+
+pub fn namespace_subscriber_add(namespace_id: &str, subscriber_id: &str) {
+    MAP_NAMESPACE.with(|p| {
+        let mut map = p.borrow_mut();
+
+        if let Some(mut ns) = map.get(&namespace_id.to_string()) {
+            if !ns.subscribers.iter().any(|i| i == subscriber_id) {
+                ns.subscribers.push(subscriber_id.to_string());
+                map.insert(namespace_id.to_string(), ns);
+            }
+        }
+    });
+}
+
+pub fn namespace_subscriber_remove(namespace_id: &str, subscriber_id: &str) {
+    MAP_NAMESPACE.with(|p| {
+        let mut map = p.borrow_mut();
+
+        if let Some(mut ns) = map.get(&namespace_id.to_string()) {
+            if ns.subscribers.iter().any(|i| i == subscriber_id) {
+                ns.subscribers.retain(|i| i != subscriber_id);
+                map.insert(namespace_id.to_string(), ns);
+            }
+        }
+    });
+}
+
+pub fn namespace_in_topic(topic_id: &str, namespace_id: &str) -> bool {
+    MAP_TOPIC.with(|p| p.borrow().get(&topic_id.to_string()))
+        .map(|topic| topic.namespaces.iter().any(|i| i == namespace_id))
+        .unwrap_or(false)
+}
+
+fn namespace_is_active(namespace_id: &str) -> bool {
+    MAP_NAMESPACE.with(|p| p.borrow().get(&namespace_id.to_string()))
+        .is_some_and(|ns| ns.active)
+}
+
+fn namespace_load(topic_id: &str, namespace_id: &str) -> usize {
+    MAP_SUBSCRIBER.with(|p| {
+        let map = p.borrow();
+
+        subscriber_ids_by_namespace(namespace_id)
+            .iter()
+            .filter(|id| map.get(*id).is_some_and(|s| s.topic == topic_id))
+            .count()
+    })
+}
+
+pub fn namespace_least_loaded(topic_id: &str) -> Option<String> {
+    let topic = MAP_TOPIC.with(|p| p.borrow().get(&topic_id.to_string()))?;
+
+    topic.namespaces
+        .iter()
+        .filter(|i| namespace_is_active(i))
+        .min_by_key(|i| namespace_load(topic_id, i))
+        .cloned()
+}
+
+/// Loads the subscriber and checks that it can be moved to the namespace.
+fn subscriber_assign_check(subscriber_id: &str, namespace_id: &str) -> Result<Subscribers, String> {
+    let subscriber: Subscribers = match MAP_SUBSCRIBER.with(|p| p.borrow().get(&subscriber_id.to_string())) {
+        Some(subscriber) => subscriber,
+        None => return Err("The subscriber was not found".to_string()),
+    };
+
+    if !namespace_in_topic(&subscriber.topic, namespace_id) {
+        return Err("The namespace is not assigned to the subscriber's topic".to_string());
+    }
+
+    Ok(subscriber)
+}
+
+pub fn subscriber_assign_namespace(subscriber_id: &str, namespace_id: &str) -> Result<(), String> {
+    let mut subscriber = subscriber_assign_check(subscriber_id, namespace_id)?;
+
+    if subscriber.namespace == namespace_id {
+        namespace_subscriber_add(namespace_id, subscriber_id);
+        return Ok(());
+    }
+
+    namespace_subscriber_remove(&subscriber.namespace, subscriber_id);
+    namespace_subscriber_add(namespace_id, subscriber_id);
+
+    subscriber.namespace = namespace_id.to_string();
+    subscriber_insert(subscriber_id, subscriber);
+
+    Ok(())
+}
+
+pub fn subscriber_delete(subscriber_id: &str) -> Option<Subscribers> {
+    let subscriber = subscriber_remove(subscriber_id)?;
+    namespace_subscriber_remove(&subscriber.namespace, subscriber_id);
+    MAP_SUBSCRIBER_CIRCUIT.with(|p| p.borrow_mut().remove(&subscriber_id.to_string()));
+
+    Some(subscriber)
+}
+
+pub fn topic_ids_by_namespace(namespace_id: &str) -> Vec<String> {
+    MAP_TOPIC.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, v)| v.namespaces.iter().any(|i| i == namespace_id))
+            .map(|(k, _)| k)
+            .collect()
+    })
+}
+
+pub fn namespace_detach(namespace_id: &str) {
+    for topic_id in topic_ids_by_namespace(namespace_id) {
+        MAP_TOPIC.with(|p| {
+            let mut map = p.borrow_mut();
+
+            if let Some(mut topic) = map.get(&topic_id) {
+                topic.namespaces.retain(|i| i != namespace_id);
+                map.insert(topic_id, topic);
+            }
+        });
+    }
+}
+
+pub fn topic_namespace_add(topic_id: &str, namespace_id: &str) -> Result<(), String> {
+    let mut topic = match MAP_TOPIC.with(|p| p.borrow().get(&topic_id.to_string())) {
+        Some(topic) => topic,
+        None => return Err("The topic was not found".to_string()),
+    };
+
+    if !MAP_NAMESPACE.with(|p| p.borrow().contains_key(&namespace_id.to_string())) {
+        return Err("The namespace was not found".to_string());
+    }
+
+    if topic.namespaces.iter().any(|i| i == namespace_id) {
+        return Err("The namespace is already assigned to the topic".to_string());
+    }
+
+    topic.namespaces.push(namespace_id.to_string());
+    topic.version = version_next(topic.version);
+    topic.updated_at = Some(ic_cdk::api::time());
+
+    MAP_TOPIC.with(|p| p.borrow_mut().insert(topic_id.to_string(), topic));
+
+    if namespace_is_active(namespace_id) {
+        topic_subscribers_rebalance(topic_id)?;
+    }
+
+    Ok(())
+}
+
+pub fn topic_namespace_remove(topic_id: &str, namespace_id: &str) -> Result<(), String> {
+    let mut topic = match MAP_TOPIC.with(|p| p.borrow().get(&topic_id.to_string())) {
+        Some(topic) => topic,
+        None => return Err("The topic was not found".to_string()),
+    };
+
+    if !topic.namespaces.iter().any(|i| i == namespace_id) {
+        return Err("The namespace is not assigned to the topic".to_string());
+    }
+
+    let has_subscribers = subscriber_ids_by_namespace(namespace_id)
+        .iter()
+        .any(|id| MAP_SUBSCRIBER.with(|p| p.borrow().get(id)).is_some_and(|s| s.topic == topic_id));
+
+    let has_alternative = topic.namespaces
+        .iter()
+        .any(|i| i != namespace_id && namespace_is_active(i));
+
+    if has_subscribers && !has_alternative {
+        return Err("The topic has no other active namespace to move the subscribers to".to_string());
+    }
+
+    topic.namespaces.retain(|i| i != namespace_id);
+    topic.version = version_next(topic.version);
+    topic.updated_at = Some(ic_cdk::api::time());
+
+    MAP_TOPIC.with(|p| p.borrow_mut().insert(topic_id.to_string(), topic));
+    namespace_evacuate(namespace_id, Some(topic_id));
+
+    Ok(())
+}
+
+/// Moves the subscribers of a namespace, optionally only those of one topic,
+/// to the least loaded active namespace of their topic. Returns the IDs of
+/// the subscribers that had nowhere to go.
+pub fn namespace_evacuate(namespace_id: &str, topic_filter: Option<&str>) -> Vec<String> {
+    let mut stranded: Vec<String> = Vec::new();
+
+    for subscriber_id in subscriber_ids_by_namespace(namespace_id) {
+        let topic_id = match MAP_SUBSCRIBER.with(|p| p.borrow().get(&subscriber_id)) {
+            Some(subscriber) => subscriber.topic,
+            None => continue,
+        };
+
+        if topic_filter.is_some_and(|t| t != topic_id) {
+            continue;
+        }
+
+        match namespace_least_loaded(&topic_id).filter(|id| id != namespace_id) {
+            Some(target) if subscriber_assign_namespace(&subscriber_id, &target).is_ok() => {}
+            _ => stranded.push(subscriber_id),
+        }
+    }
+
+    stranded
+}
+
+/// Redistributes a topic's subscribers so its active namespaces differ by at
+/// most one subscriber. Returns the number of subscribers moved. Every move is
+/// planned and checked before the first one is made, so an error leaves the
+/// topic as it was.
+pub fn topic_subscribers_rebalance(topic_id: &str) -> Result<u64, String> {
+    let topic = match MAP_TOPIC.with(|p| p.borrow().get(&topic_id.to_string())) {
+        Some(topic) => topic,
+        None => return Err("The topic was not found".to_string()),
+    };
+
+    let active: Vec<String> = topic.namespaces
+        .iter()
+        .filter(|i| namespace_is_active(i))
+        .cloned()
+        .collect();
+
+    if active.is_empty() {
+        return Err("The topic has no active namespaces".to_string());
+    }
+
+    let mut assigned: BTreeMap<String, Vec<String>> = active.iter().map(|id| (id.clone(), Vec::new())).collect();
+    let mut movable: Vec<String> = Vec::new();
+    let subscriber_ids = subscriber_ids_by_topic(topic_id);
+
+    for subscriber_id in subscriber_ids.iter() {
+        let namespace_id = MAP_SUBSCRIBER.with(|p| p.borrow().get(subscriber_id)).map(|s| s.namespace);
+
+        match namespace_id.and_then(|id| assigned.get_mut(&id)) {
+            Some(ids) => ids.push(subscriber_id.clone()),
+            None => movable.push(subscriber_id.clone()),
+        }
+    }
+
+    // The namespaces that already hold the most subscribers keep the extra
+    // slots, which keeps the number of moves down
+    let base = subscriber_ids.len() / active.len();
+    let extra = subscriber_ids.len() % active.len();
+
+    let mut order: Vec<String> = active.clone();
+    order.sort_by_key(|id| Reverse(assigned[id].len()));
+
+    let targets: BTreeMap<String, usize> = order
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (id.clone(), base + usize::from(i < extra)))
+        .collect();
+
+    for (id, ids) in assigned.iter_mut() {
+        while ids.len() > targets[id] {
+            movable.extend(ids.pop());
+        }
+    }
+
+    let mut moves: Vec<(String, String)> = Vec::new();
+
+    for id in order.iter() {
+        while assigned[id].len() < targets[id] {
+            let subscriber_id = match movable.pop() {
+                Some(subscriber_id) => subscriber_id,
+                None => break,
+            };
+
+            assigned.entry(id.clone()).or_default().push(subscriber_id.clone());
+            moves.push((subscriber_id, id.clone()));
+        }
+    }
+
+    for (subscriber_id, namespace_id) in moves.iter() {
+        subscriber_assign_check(subscriber_id, namespace_id)?;
+    }
+
+    // Checked above, a failure here is a bug and the trap rolls back the
+    // moves already made
+    for (subscriber_id, namespace_id) in moves.iter() {
+        if let Err(err) = subscriber_assign_namespace(subscriber_id, namespace_id) {
+            ic_cdk::trap(&format!("Rebalancing topic {} failed: {}", topic_id, err));
+        }
+    }
+
+    Ok(moves.len() as u64)
+}
@@ -0,0 +1,381 @@
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::ops::Bound;
+use crate::audit::audit_record;
+use crate::config::config_push;
+use crate::index::{
+    topic_id_by_name, topic_name_index_set,
+    namespace_id_by_name, namespace_name_index_set,
+    subscriber_insert,
+};
+use crate::relations::{namespace_subscriber_add, namespace_in_topic};
+use crate::types::{
+    AuditEntity, Canisters, Namespaces, Subscribers, Topic, Topics,
+    RegistrySnapshot, SnapshotSection, ExportCursor, ExportChunk,
+    ImportMode, ImportConflict, ImportConflictKind, ImportReport,
+};
+use crate::utils::caller_is_controller;
+use crate::validation::{
+    check_id, check_name, check_label, check_description, check_callback,
+    check_principal, check_list_len, validation_result, MAX_TOPIC_NAMESPACES,
+};
+use crate::{
+    Memory, MAP_TOPIC, MAP_NAMESPACE, MAP_SUBSCRIBER, MAP_CANISTER,
+    MAP_TOPIC_NAME, MAP_NAMESPACE_NAME,
+    MAP_SUBSCRIBER_BY_TOPIC, MAP_SUBSCRIBER_BY_NAMESPACE, MAP_SUBSCRIBER_BY_CANISTER,
+    MAP_SUBSCRIBER_CIRCUIT,
+};
+
+/// Bump when the snapshot layout changes. Imports accept this version and
+/// every older one.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// Records per export chunk, across all sections, so a chunk stays well
+/// below the message size limit. Namespaces are exported without their
+/// subscriber list, the one field that grows with the registry.
+const EXPORT_CHUNK_RECORDS: usize = 500;
+
+
+/******************************************************/
+//
+//  SNAPSHOTS
+//
+//  Move a topology between deployments or back it up
+//  before an upgrade
+//
+//  registry_export     One chunk of a snapshot of every map
+//  registry_import     Load a snapshot by merging or replacing
+//
+/******************************************************/
+
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn registry_export(cursor: Option<ExportCursor>) -> ExportChunk {
+    let mut cursor = cursor.unwrap_or(ExportCursor {
+        section: SnapshotSection::Namespaces,
+        start_after: None,
+    });
+
+    let mut snapshot = RegistrySnapshot {
+        format_version: SNAPSHOT_FORMAT_VERSION,
+        exported_at: ic_cdk::api::time(),
+        namespaces: Vec::new(),
+        topics: Vec::new(),
+        subscribers: Vec::new(),
+        canisters: Vec::new(),
+    };
+
+    let mut remaining = EXPORT_CHUNK_RECORDS;
+
+    loop {
+        let start = &cursor.start_after;
+
+        let keys: Vec<String> = match cursor.section {
+            SnapshotSection::Namespaces => {
+                let entries = MAP_NAMESPACE.with(|p| section_entries(&p.borrow(), start, remaining));
+                let keys = entries.iter().map(|(k, _)| k.clone()).collect();
+                // Import rebuilds the list from the subscribers section
+                snapshot.namespaces.extend(entries.into_iter().map(|(k, v)| Namespaces {
+                    id: k,
+                    subscribers: Vec::new(),
+                    ..v
+                }));
+                keys
+            },
+            SnapshotSection::Topics => {
+                let entries = MAP_TOPIC.with(|p| section_entries(&p.borrow(), start, remaining));
+                let keys = entries.iter().map(|(k, _)| k.clone()).collect();
+                snapshot.topics.extend(entries.into_iter().map(|(k, v)| Topics {
+                    id: k,
+                    name: v.name,
+                    description: v.description,
+                    namespaces: v.namespaces,
+                    active: v.active,
+                    version: v.version,
+                    updated_at: v.updated_at,
+                }));
+                keys
+            },
+            SnapshotSection::Subscribers => {
+                let entries = MAP_SUBSCRIBER.with(|p| section_entries(&p.borrow(), start, remaining));
+                let keys = entries.iter().map(|(k, _)| k.clone()).collect();
+                snapshot.subscribers.extend(entries.into_iter().map(|(k, v)| Subscribers { id: k, ..v }));
+                keys
+            },
+            SnapshotSection::Canisters => {
+                let entries = MAP_CANISTER.with(|p| section_entries(&p.borrow(), start, remaining));
+                let keys = entries.iter().map(|(k, _)| k.clone()).collect();
+                snapshot.canisters.extend(entries.into_iter().map(|(k, v)| Canisters { canister_name: k, ..v }));
+                keys
+            },
+        };
+
+        // The chunk is full; the next one continues after the last key
+        if keys.len() == remaining {
+            let next = ExportCursor {
+                section: cursor.section,
+                start_after: keys.last().cloned(),
+            };
+            return ExportChunk { snapshot, next: Some(next) };
+        }
+
+        remaining -= keys.len();
+
+        match section_next(cursor.section) {
+            Some(section) => cursor = ExportCursor { section, start_after: None },
+            None => return ExportChunk { snapshot, next: None },
+        }
+    }
+}
+
+/// Imports namespaces, topics, subscribers and canisters in that order so
+/// every reference can be checked against records that already exist.
+/// Records that cannot be imported are reported as conflicts and skipped.
+/// An export is imported chunk by chunk in the order it was exported, with
+/// `first_chunk` set on the first one. A chunk that changes the canisters
+/// publishes and pushes the new config.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn registry_import(snapshot: RegistrySnapshot, mode: ImportMode, first_chunk: bool) -> Result<ImportReport, String> {
+    if snapshot.format_version > SNAPSHOT_FORMAT_VERSION {
+        return Err(format!(
+            "The snapshot has format version {} but this build imports up to version {}",
+            snapshot.format_version,
+            SNAPSHOT_FORMAT_VERSION,
+        ));
+    }
+
+    let clear = mode == ImportMode::Replace && first_chunk;
+
+    if clear {
+        registry_clear();
+    }
+
+    let mut report = ImportReport::default();
+
+    for namespace in snapshot.namespaces {
+        namespace_import(namespace, &mut report);
+    }
+
+    for topic in snapshot.topics {
+        topic_import(topic, &mut report);
+    }
+
+    for subscriber in snapshot.subscribers {
+        subscriber_import(subscriber, &mut report);
+    }
+
+    for canister in snapshot.canisters {
+        canister_import(canister, &mut report);
+    }
+
+    let summary = format!(
+        "mode={} cleared={} namespaces={} topics={} subscribers={} canisters={} conflicts={}",
+        if mode == ImportMode::Replace { "replace" } else { "merge" },
+        clear,
+        report.imported.namespaces,
+        report.imported.topics,
+        report.imported.subscribers,
+        report.imported.canisters,
+        report.conflicts.len(),
+    );
+    audit_record("registry_import", AuditEntity::Registry, "registry", None, Some(summary));
+
+    // Publish the new canister set and push it, so no canister keeps the
+    // peers of the replaced registry
+    if clear || report.imported.canisters > 0 {
+        config_push();
+    }
+
+    Ok(report)
+}
+
+fn section_entries<V: Storable>(
+    map: &StableBTreeMap<String, V, Memory>,
+    start_after: &Option<String>,
+    limit: usize,
+) -> Vec<(String, V)> {
+    let start = match start_after {
+        Some(key) => Bound::Excluded(key.clone()),
+        None => Bound::Unbounded,
+    };
+
+    map.range((start, Bound::Unbounded)).take(limit).collect()
+}
+
+fn section_next(section: SnapshotSection) -> Option<SnapshotSection> {
+    match section {
+        SnapshotSection::Namespaces => Some(SnapshotSection::Topics),
+        SnapshotSection::Topics => Some(SnapshotSection::Subscribers),
+        SnapshotSection::Subscribers => Some(SnapshotSection::Canisters),
+        SnapshotSection::Canisters => None,
+    }
+}
+
+fn registry_clear() {
+    MAP_TOPIC.with(|p| p.borrow_mut().clear_new());
+    MAP_NAMESPACE.with(|p| p.borrow_mut().clear_new());
+    MAP_SUBSCRIBER.with(|p| p.borrow_mut().clear_new());
+    MAP_CANISTER.with(|p| p.borrow_mut().clear_new());
+    MAP_TOPIC_NAME.with(|p| p.borrow_mut().clear_new());
+    MAP_NAMESPACE_NAME.with(|p| p.borrow_mut().clear_new());
+    MAP_SUBSCRIBER_BY_TOPIC.with(|p| p.borrow_mut().clear_new());
+    MAP_SUBSCRIBER_BY_NAMESPACE.with(|p| p.borrow_mut().clear_new());
+    MAP_SUBSCRIBER_BY_CANISTER.with(|p| p.borrow_mut().clear_new());
+    MAP_SUBSCRIBER_CIRCUIT.with(|p| p.borrow_mut().clear_new());
+}
+
+fn conflict(report: &mut ImportReport, section: SnapshotSection, id: &str, kind: ImportConflictKind, message: &str) {
+    report.conflicts.push(ImportConflict {
+        section,
+        id: id.to_string(),
+        kind,
+        message: message.to_string(),
+    });
+}
+
+/// The subscriber list is rebuilt from the imported subscribers, so it is
+/// not compared and an existing namespace keeps its current list.
+fn namespace_import(namespace: Namespaces, report: &mut ImportReport) {
+    let section = SnapshotSection::Namespaces;
+
+    let mut errors = Vec::new();
+    check_id(&mut errors, "id", &namespace.id);
+    check_name(&mut errors, "name", &namespace.name);
+    check_description(&mut errors, "description", &namespace.description);
+    if let Err(err) = validation_result(errors) {
+        return conflict(report, section, &namespace.id, ImportConflictKind::Invalid, &err.to_string());
+    }
+
+    if let Some(existing) = MAP_NAMESPACE.with(|p| p.borrow().get(&namespace.id)) {
+        if existing.name == namespace.name
+            && existing.description == namespace.description
+            && existing.active == namespace.active {
+            report.unchanged.namespaces += 1;
+        } else {
+            conflict(report, section, &namespace.id, ImportConflictKind::IdExists, "A different namespace with this id exists");
+        }
+        return;
+    }
+
+    if namespace_id_by_name(&namespace.name).is_some() {
+        return conflict(report, section, &namespace.id, ImportConflictKind::NameTaken, "Another namespace has this name");
+    }
+
+    namespace_name_index_set(&namespace.name, &namespace.id);
+    MAP_NAMESPACE.with(|p| p.borrow_mut().insert(namespace.id.clone(), Namespaces {
+        subscribers: Vec::new(),
+        ..namespace
+    }));
+    report.imported.namespaces += 1;
+}
+
+fn topic_import(topic: Topics, report: &mut ImportReport) {
+    let section = SnapshotSection::Topics;
+
+    let mut errors = Vec::new();
+    check_id(&mut errors, "id", &topic.id);
+    check_name(&mut errors, "name", &topic.name);
+    check_description(&mut errors, "description", &topic.description);
+    check_list_len(&mut errors, "namespaces", topic.namespaces.len(), MAX_TOPIC_NAMESPACES);
+    for namespace in topic.namespaces.iter() {
+        check_id(&mut errors, "namespaces", namespace);
+    }
+    if let Err(err) = validation_result(errors) {
+        return conflict(report, section, &topic.id, ImportConflictKind::Invalid, &err.to_string());
+    }
+
+    let record = Topic {
+        name: topic.name,
+        description: topic.description,
+        namespaces: topic.namespaces,
+        active: topic.active,
+        version: topic.version,
+        updated_at: topic.updated_at,
+    };
+
+    if let Some(existing) = MAP_TOPIC.with(|p| p.borrow().get(&topic.id)) {
+        if existing == record {
+            report.unchanged.topics += 1;
+        } else {
+            conflict(report, section, &topic.id, ImportConflictKind::IdExists, "A different topic with this id exists");
+        }
+        return;
+    }
+
+    if topic_id_by_name(&record.name).is_some() {
+        return conflict(report, section, &topic.id, ImportConflictKind::NameTaken, "Another topic has this name");
+    }
+
+    let missing = record.namespaces.iter().find(|ns| !MAP_NAMESPACE.with(|p| p.borrow().contains_key(*ns)));
+    if let Some(ns) = missing {
+        let message = format!("The namespace {} was not found", ns);
+        return conflict(report, section, &topic.id, ImportConflictKind::MissingReference, &message);
+    }
+
+    topic_name_index_set(&record.name, &topic.id);
+    MAP_TOPIC.with(|p| p.borrow_mut().insert(topic.id, record));
+    report.imported.topics += 1;
+}
+
+fn subscriber_import(subscriber: Subscribers, report: &mut ImportReport) {
+    let section = SnapshotSection::Subscribers;
+
+    let mut errors = Vec::new();
+    check_id(&mut errors, "id", &subscriber.id);
+    check_principal(&mut errors, "canister_id", &subscriber.canister_id);
+    check_callback(&mut errors, "callback", &subscriber.callback);
+    check_label(&mut errors, "name", &subscriber.name);
+    check_description(&mut errors, "description", &subscriber.description);
+    check_id(&mut errors, "topic", &subscriber.topic);
+    check_id(&mut errors, "namespace", &subscriber.namespace);
+    if let Err(err) = validation_result(errors) {
+        return conflict(report, section, &subscriber.id, ImportConflictKind::Invalid, &err.to_string());
+    }
+
+    if let Some(existing) = MAP_SUBSCRIBER.with(|p| p.borrow().get(&subscriber.id)) {
+        if existing == subscriber {
+            report.unchanged.subscribers += 1;
+        } else {
+            conflict(report, section, &subscriber.id, ImportConflictKind::IdExists, "A different subscriber with this id exists");
+        }
+        return;
+    }
+
+    if !MAP_TOPIC.with(|p| p.borrow().contains_key(&subscriber.topic)) {
+        return conflict(report, section, &subscriber.id, ImportConflictKind::MissingReference, "The topic was not found");
+    }
+
+    if !namespace_in_topic(&subscriber.topic, &subscriber.namespace) {
+        return conflict(report, section, &subscriber.id, ImportConflictKind::MissingReference, "The namespace is not assigned to the topic");
+    }
+
+    let id = subscriber.id.clone();
+    let namespace = subscriber.namespace.clone();
+
+    subscriber_insert(&id, subscriber);
+    namespace_subscriber_add(&namespace, &id);
+    report.imported.subscribers += 1;
+}
+
+fn canister_import(canister: Canisters, report: &mut ImportReport) {
+    let section = SnapshotSection::Canisters;
+
+    let mut errors = Vec::new();
+    check_principal(&mut errors, "canister_id", &canister.canister_id);
+    check_name(&mut errors, "canister_name", &canister.canister_name);
+    check_label(&mut errors, "name", &canister.name);
+    check_description(&mut errors, "description", &canister.description);
+    if let Err(err) = validation_result(errors) {
+        return conflict(report, section, &canister.canister_name, ImportConflictKind::Invalid, &err.to_string());
+    }
+
+    if let Some(existing) = MAP_CANISTER.with(|p| p.borrow().get(&canister.canister_name)) {
+        if existing == canister {
+            report.unchanged.canisters += 1;
+        } else {
+            conflict(report, section, &canister.canister_name, ImportConflictKind::IdExists, "A different canister with this name exists");
+        }
+        return;
+    }
+
+    MAP_CANISTER.with(|p| p.borrow_mut().insert(canister.canister_name.clone(), canister));
+    report.imported.canisters += 1;
+}
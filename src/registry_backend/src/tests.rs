@@ -0,0 +1,126 @@
+use candid::Principal;
+use ic_stable_structures::Storable;
+use std::borrow::Cow;
+use bus_common::record::record_version;
+use crate::types::{Canisters, Namespaces, Subscribers, Topic};
+
+
+/******************************************************/
+//
+//  STORAGE FIXTURES
+//
+//  Hex encoded records as they were written by earlier
+//  releases. Every fixture must keep decoding.
+//
+/******************************************************/
+
+const TOPIC_V0: &str = include_str!("../fixtures/topic_v0.hex");
+const TOPIC_V0_VERSIONED: &str = include_str!("../fixtures/topic_v0_versioned.hex");
+const NAMESPACE_V0: &str = include_str!("../fixtures/namespace_v0.hex");
+const SUBSCRIBER_V0: &str = include_str!("../fixtures/subscriber_v0.hex");
+const SUBSCRIBER_V0_INVALID_PRINCIPAL: &str = include_str!("../fixtures/subscriber_v0_invalid_principal.hex");
+const SUBSCRIBER_V0_PRINCIPAL: &str = include_str!("../fixtures/subscriber_v0_principal.hex");
+const CANISTER_V0: &str = include_str!("../fixtures/canister_v0.hex");
+
+fn fixture(hex: &str) -> Vec<u8> {
+    let hex = hex.trim();
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// Decodes a stored record, writes it back and decodes the result, which is
+/// what the `post_upgrade` migrations do.
+fn upgrade<T: Storable>(hex: &str) -> T {
+    let bytes = fixture(hex);
+    assert_eq!(record_version(&bytes), Ok(0));
+
+    let record = T::from_bytes(Cow::Owned(bytes));
+    let rewritten = record.to_bytes().into_owned();
+    assert_eq!(record_version(&rewritten), Ok(1));
+
+    T::from_bytes(Cow::Owned(rewritten))
+}
+
+#[test]
+fn topic_v0_upgrades() {
+    let topic: Topic = upgrade(TOPIC_V0);
+
+    assert_eq!(topic.name, "orders");
+    assert_eq!(topic.namespaces, vec!["ns-1", "ns-2"]);
+    assert!(topic.active);
+    assert_eq!(topic.version, None);
+    assert_eq!(topic.updated_at, None);
+}
+
+#[test]
+fn topic_v0_with_version_fields_upgrades() {
+    let topic: Topic = upgrade(TOPIC_V0_VERSIONED);
+
+    assert_eq!(topic.version, Some(3));
+    assert_eq!(topic.updated_at, Some(1700000000000000000));
+}
+
+#[test]
+fn namespace_v0_upgrades() {
+    let ns: Namespaces = upgrade(NAMESPACE_V0);
+
+    assert_eq!(ns.id, "ns-1");
+    assert_eq!(ns.name, "primary");
+    assert_eq!(ns.subscribers, vec!["sub-1"]);
+    assert_eq!(ns.version, None);
+}
+
+#[test]
+fn subscriber_v0_with_text_canister_id_upgrades() {
+    let subscriber: Subscribers = upgrade(SUBSCRIBER_V0);
+
+    assert_eq!(subscriber.canister_id, Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap());
+    assert_eq!(subscriber.callback, "on_message");
+    assert_eq!(subscriber.topic, "topic-1");
+    assert_eq!(subscriber.version, None);
+}
+
+#[test]
+fn subscriber_v0_with_invalid_canister_id_decodes_as_anonymous() {
+    let subscriber: Subscribers = upgrade(SUBSCRIBER_V0_INVALID_PRINCIPAL);
+
+    assert_eq!(subscriber.id, "sub-2");
+    assert_eq!(subscriber.canister_id, Principal::anonymous());
+}
+
+#[test]
+fn subscriber_v0_with_principal_canister_id_upgrades() {
+    let subscriber: Subscribers = upgrade(SUBSCRIBER_V0_PRINCIPAL);
+
+    assert_eq!(subscriber.canister_id, Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap());
+    assert_eq!(subscriber.version, Some(2));
+}
+
+#[test]
+fn canister_v0_upgrades() {
+    let canister: Canisters = upgrade(CANISTER_V0);
+
+    assert_eq!(canister.canister_name, "queue_backend");
+    assert_eq!(canister.canister_id, Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap());
+    assert_eq!(canister.version, None);
+}
+
+#[test]
+fn records_larger_than_the_old_bound_round_trip() {
+    let topic = Topic {
+        name: "orders".to_string(),
+        description: "x".repeat(4096),
+        namespaces: (0..100).map(|i| format!("namespace-{}", i)).collect(),
+        active: true,
+        version: Some(1),
+        updated_at: None,
+    };
+
+    let decoded = Topic::from_bytes(topic.to_bytes());
+
+    assert_eq!(decoded.description.len(), 4096);
+    assert_eq!(decoded.namespaces.len(), 100);
+}
@@ -1,10 +1,11 @@
-use candid::{CandidType, Decode, Deserialize, Encode};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
 use ic_stable_structures::{Storable, storable::Bound};
-use std::{borrow::Cow};
+use bus_common::record::{record_encode, record_decode, VersionedRecord};
+use std::{borrow::Cow, fmt};
 
 
 
-const MAX_VALUE_SIZE: u32 = 1024;
+const MAX_KEY_SIZE: u32 = 1024;
 
 
 /******************************************************/
@@ -35,6 +36,70 @@ pub struct CallSubscribersResponse {
     pub result: Vec<Subscribers>,
 }
 
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ValidationCode {
+    Required,
+    TooLong,
+    TooMany,
+    InvalidCharacters,
+    InvalidPrincipal,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub code: ValidationCode,
+    pub message: String,
+}
+
+/// Error of the register/update endpoints. `Invalid` lists every problem
+/// with the input; `Rejected` is returned when valid input conflicts with
+/// the stored registry.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum RegistryError {
+    Invalid(Vec<FieldError>),
+    Rejected(String),
+}
+
+impl From<String> for RegistryError {
+    fn from(message: String) -> Self {
+        RegistryError::Rejected(message)
+    }
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::Invalid(errors) => {
+                let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+                write!(f, "{}", messages.join("; "))
+            }
+            RegistryError::Rejected(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum DeleteMode {
+    Restrict,
+    Cascade,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct ListArgs {
+    pub start_after: Option<String>,
+    pub limit: Option<u64>,
+    pub active_only: Option<bool>,
+    pub name_prefix: Option<String>,
+    pub topic: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+}
+
 /******************************************************/
 //
 //  STRUCTS
@@ -51,32 +116,38 @@ pub struct Namespace {
     pub active: bool,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
 pub struct Namespaces {
     pub id: String,
     pub name: String,
     pub description: String,
     pub subscribers: Vec<String>,
     pub active: bool,
+    pub version: Option<u64>,
+    pub updated_at: Option<u64>,
 }
 
 // TOPIC ///////////////////////////////////////////
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
 pub struct Topic {
     pub name: String,
     pub description: String,
     pub namespaces: Vec<String>,
     pub active: bool,
+    pub version: Option<u64>,
+    pub updated_at: Option<u64>,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct Topics {
     pub id: String,
     pub name: String,
     pub description: String,
     pub namespaces: Vec<String>,
     pub active: bool,
+    pub version: Option<u64>,
+    pub updated_at: Option<u64>,
 }
 
 // SUBSCRIBER ///////////////////////////////////////////
@@ -84,7 +155,7 @@ pub struct Topics {
 #[derive(CandidType, Deserialize)]
 pub struct Subscriber {
     pub id: String,
-    pub canister_id: String,
+    pub canister_id: Principal,
     pub callback: String,
     pub name: String,
     pub description: String,
@@ -93,28 +164,357 @@ pub struct Subscriber {
     pub active: bool,
 }
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
 pub struct Subscribers {
     pub id: String,
-    pub canister_id: String,
+    pub canister_id: Principal,
     pub callback: String,
     pub name: String,
     pub description: String,
     pub topic: String,
     pub namespace: String,
     pub active: bool,
+    pub version: Option<u64>,
+    pub updated_at: Option<u64>,
 }
 
-// CANISTERS ///////////////////////////////////////////
+// UPDATES ///////////////////////////////////////////
+
+#[derive(CandidType, Deserialize)]
+pub struct NamespaceUpdate {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub active: Option<bool>,
+    pub expected_version: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TopicUpdate {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub active: Option<bool>,
+    pub expected_version: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SubscriberUpdate {
+    pub canister_id: Option<Principal>,
+    pub callback: Option<String>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub active: Option<bool>,
+    pub expected_version: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CanisterUpdate {
+    pub canister_id: Option<Principal>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub active: Option<bool>,
+    pub expected_version: u64,
+}
+
+// CONSISTENCY ///////////////////////////////////////////
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum RegistryIssue {
+    SubscriberTopicMissing { subscriber_id: String, topic_id: String },
+    SubscriberNamespaceMissing { subscriber_id: String, namespace_id: String },
+    SubscriberNamespaceNotInTopic { subscriber_id: String, namespace_id: String, topic_id: String },
+    SubscriberPrincipalInvalid { subscriber_id: String, canister_id: Principal },
+    TopicNamespaceMissing { topic_id: String, namespace_id: String },
+    TopicNameDuplicate { name: String, topic_ids: Vec<String> },
+    NamespaceNameDuplicate { name: String, namespace_ids: Vec<String> },
+    NamespaceSubscriberStale { namespace_id: String, subscriber_id: String },
+    NamespaceSubscriberUnlisted { namespace_id: String, subscriber_id: String },
+    CanisterPrincipalInvalid { canister_name: String, canister_id: Principal },
+    IndexOutOfSync { index: String },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RegistryReport {
+    pub issues: Vec<RegistryIssue>,
+    pub actions: Vec<String>,
+    pub dry_run: bool,
+}
+
+// SNAPSHOTS ///////////////////////////////////////////
+
+/// One chunk of an export, or a full snapshot once the chunks are
+/// concatenated. `format_version` lets newer builds import older snapshots.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RegistrySnapshot {
+    pub format_version: u32,
+    pub exported_at: u64,
+    pub namespaces: Vec<Namespaces>,
+    pub topics: Vec<Topics>,
+    pub subscribers: Vec<Subscribers>,
+    pub canisters: Vec<Canisters>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, PartialOrd)]
+pub enum SnapshotSection {
+    Namespaces,
+    Topics,
+    Subscribers,
+    Canisters,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ExportCursor {
+    pub section: SnapshotSection,
+    pub start_after: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ExportChunk {
+    pub snapshot: RegistrySnapshot,
+    pub next: Option<ExportCursor>,
+}
+
+/// `Replace` clears the registry before the first chunk of an import only;
+/// the remaining chunks are added to what the first one imported.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum ImportMode {
+    Merge,
+    Replace,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum ImportConflictKind {
+    IdExists,
+    NameTaken,
+    MissingReference,
+    Invalid,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ImportConflict {
+    pub section: SnapshotSection,
+    pub id: String,
+    pub kind: ImportConflictKind,
+    pub message: String,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct ImportCounts {
+    pub namespaces: u64,
+    pub topics: u64,
+    pub subscribers: u64,
+    pub canisters: u64,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct ImportReport {
+    pub imported: ImportCounts,
+    pub unchanged: ImportCounts,
+    pub conflicts: Vec<ImportConflict>,
+}
+
+// AUDIT ///////////////////////////////////////////
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum AuditEntity {
+    Namespace,
+    Topic,
+    Subscriber,
+    Canister,
+    Registry,
+}
 
+/// One mutating call. `before` and `after` summarize the entity as it was
+/// before and after the call; they are `None` when it did not exist.
 #[derive(CandidType, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub endpoint: String,
+    pub entity: AuditEntity,
+    pub entity_id: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// Filters are combined; `from` and `to` are inclusive timestamps.
+#[derive(CandidType, Deserialize, Default)]
+pub struct AuditArgs {
+    pub entity_id: Option<String>,
+    pub caller: Option<Principal>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub start_after: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AuditPage {
+    pub items: Vec<AuditEntry>,
+    pub next: Option<u64>,
+}
+
+// CONFIG ///////////////////////////////////////////
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub struct ConfigEntry {
+    pub canister_name: String,
+    pub canister_id: Principal,
+}
+
+/// The canister IDs every service canister needs to reach its peers. A new
+/// version is published whenever the registered canisters change.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ConfigDocument {
+    pub version: u64,
+    pub published_at: u64,
+    pub canisters: Vec<ConfigEntry>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub enum ConfigPushState {
+    Pending,
+    InFlight,
+    Applied,
+    Failed,
+}
+
+/// Delivery state of the configuration for one canister. `attempts` counts
+/// the failed pushes since the last successful one.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ConfigTarget {
+    pub canister_name: String,
+    pub canister_id: Principal,
+    pub applied_version: Option<u64>,
+    pub state: ConfigPushState,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub updated_at: u64,
+}
+
+// CIRCUITS ///////////////////////////////////////////
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Delivery health of a subscriber as last reported by the queue.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CircuitStatus {
+    pub subscriber_id: String,
+    pub state: CircuitState,
+    pub failures: u64,
+    pub opened_at: Option<u64>,
+    pub buffered: u64,
+    pub dropped: u64,
+    pub updated_at: u64,
+}
+
+/// A subscriber with its circuit. Subscribers the queue never reported on
+/// have no circuit, which means they are healthy.
+#[derive(CandidType, Deserialize)]
+pub struct SubscriberView {
+    pub subscriber: Subscribers,
+    pub circuit: Option<CircuitStatus>,
+}
+
+// INDEXES ///////////////////////////////////////////
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IndexKey {
+    pub key: String,
+    pub id: String,
+}
+
+// CANISTERS ///////////////////////////////////////////
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
 pub struct Canisters {
+    pub id: String,
+    pub canister_id: Principal,
+    pub canister_name: String,
+    pub name: String,
+    pub description: String,
+    pub active: bool,
+    pub version: Option<u64>,
+    pub updated_at: Option<u64>,
+}
+
+
+/******************************************************/
+//
+//  LEGACY
+//
+//  Encodings written before canister IDs were stored as
+//  principals, read as version 0 of their records. Values that
+//  do not parse become the anonymous principal and are removed
+//  by the migration.
+//
+/******************************************************/
+
+fn legacy_principal(text: &str) -> Principal {
+    Principal::from_text(text).unwrap_or(Principal::anonymous())
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SubscribersLegacy {
+    pub id: String,
+    pub canister_id: String,
+    pub callback: String,
+    pub name: String,
+    pub description: String,
+    pub topic: String,
+    pub namespace: String,
+    pub active: bool,
+    pub version: Option<u64>,
+    pub updated_at: Option<u64>,
+}
+
+impl From<SubscribersLegacy> for Subscribers {
+    fn from(v: SubscribersLegacy) -> Self {
+        Subscribers {
+            id: v.id,
+            canister_id: legacy_principal(&v.canister_id),
+            callback: v.callback,
+            name: v.name,
+            description: v.description,
+            topic: v.topic,
+            namespace: v.namespace,
+            active: v.active,
+            version: v.version,
+            updated_at: v.updated_at,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CanistersLegacy {
     pub id: String,
     pub canister_id: String,
     pub canister_name: String,
     pub name: String,
     pub description: String,
     pub active: bool,
+    pub version: Option<u64>,
+    pub updated_at: Option<u64>,
+}
+
+impl From<CanistersLegacy> for Canisters {
+    fn from(v: CanistersLegacy) -> Self {
+        Canisters {
+            id: v.id,
+            canister_id: legacy_principal(&v.canister_id),
+            canister_name: v.canister_name,
+            name: v.name,
+            description: v.description,
+            active: v.active,
+            version: v.version,
+            updated_at: v.updated_at,
+        }
+    }
 }
 
 
@@ -122,45 +522,94 @@ pub struct Canisters {
 //
 //  STORABLES
 //
+//  Records are stored through `bus_common::record`, which
+//  prefixes them with their schema version. Version 0 is the
+//  plain Candid encoding written before versioning.
+//
 /******************************************************/
 
 // NAMESPACE ///////////////////////////////////////////
 
+impl VersionedRecord for Namespaces {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Decode!(payload, Self).map_err(|err| err.to_string()),
+            _ => Err(format!("Unknown namespace record version {}", version)),
+        }
+    }
+}
+
 impl Storable for Namespaces {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(record_encode(self))
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 // TOPIC ///////////////////////////////////////////
 
+impl VersionedRecord for Topic {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Decode!(payload, Self).map_err(|err| err.to_string()),
+            _ => Err(format!("Unknown topic record version {}", version)),
+        }
+    }
+}
+
 impl Storable for Topic {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(record_encode(self))
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 // SUBSCRIBER ///////////////////////////////////////////
 
+impl VersionedRecord for Subscribers {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Decode!(payload, Self)
+                .or_else(|_| Decode!(payload, SubscribersLegacy).map(Self::from))
+                .map_err(|err| err.to_string()),
+            _ => Err(format!("Unknown subscriber record version {}", version)),
+        }
+    }
+}
+
 impl Storable for Subscribers {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(record_encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// INDEXES ///////////////////////////////////////////
+
+/// Index keys are derived from the records and rebuilt when they disagree
+/// with them, so they keep the plain bounded encoding.
+impl Storable for IndexKey {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -170,24 +619,120 @@ impl Storable for Subscribers {
     }
 
     const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
+        max_size: MAX_KEY_SIZE,
         is_fixed_size: false,
     };
 }
 
+// AUDIT ///////////////////////////////////////////
+
+impl VersionedRecord for AuditEntry {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("Unknown audit entry version {}", version))
+    }
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(record_encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// CONFIG ///////////////////////////////////////////
+
+impl VersionedRecord for ConfigDocument {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("Unknown config document version {}", version))
+    }
+}
+
+impl Storable for ConfigDocument {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(record_encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl VersionedRecord for ConfigTarget {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("Unknown config target version {}", version))
+    }
+}
+
+impl Storable for ConfigTarget {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(record_encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// CIRCUITS ///////////////////////////////////////////
+
+impl VersionedRecord for CircuitStatus {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("Unknown circuit status version {}", version))
+    }
+}
+
+impl Storable for CircuitStatus {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(record_encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 // CANISTERS ///////////////////////////////////////////
 
+impl VersionedRecord for Canisters {
+    const VERSION: u8 = 1;
+
+    fn upgrade(version: u8, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Decode!(payload, Self)
+                .or_else(|_| Decode!(payload, CanistersLegacy).map(Self::from))
+                .map_err(|err| err.to_string()),
+            _ => Err(format!("Unknown canister record version {}", version)),
+        }
+    }
+}
+
 impl Storable for Canisters {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(record_encode(self))
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        record_decode(bytes.as_ref()).unwrap_or_else(|err| ic_cdk::trap(&err))
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
 }
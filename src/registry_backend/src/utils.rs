@@ -1,4 +1,11 @@
 use uuid_by_string::generate_uuid::{generate_uuid};
+use candid::Principal;
+use std::ops::Bound;
+use crate::types::{ListArgs, Page};
+
+const DEFAULT_PAGE_SIZE: u64 = 100;
+const MAX_PAGE_SIZE: u64 = 500;
+pub const MAX_PAGE_SCAN: u64 = 5000;
 
 
 /******************************************************/
@@ -7,10 +14,41 @@ use uuid_by_string::generate_uuid::{generate_uuid};
 //
 /******************************************************/
 
-#[ic_cdk_macros::query]
 pub fn create_uuid() -> String {
-    let ts = ic_cdk::api::time().to_string();
-    generate_uuid(&ts)
+    bus_common::uuid::create_uuid()
+}
+
+/// Optimistic concurrency check for the `*_update` endpoints. Records stored
+/// before versioning was introduced count as version 0.
+pub fn version_check(current: Option<u64>, expected: u64) -> Result<(), String> {
+    let current = current.unwrap_or(0);
+
+    if current != expected {
+        return Err(format!("Version conflict: expected version {} but the record is at version {}", expected, current));
+    }
+    Ok(())
+}
+
+pub fn version_next(current: Option<u64>) -> Option<u64> {
+    Some(current.unwrap_or(0) + 1)
+}
+
+/// The anonymous principal cannot host a callback, and it is what legacy
+/// records with unparsable canister IDs decode to.
+pub fn principal_check(canister_id: &Principal) -> Result<(), String> {
+    if *canister_id == Principal::anonymous() {
+        return Err("The canister id cannot be the anonymous principal".to_string());
+    }
+    Ok(())
+}
+
+/// Guard for admin endpoints.
+pub fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("Only a controller of the registry can call this method".to_string())
+    }
 }
 
 pub fn get_variable_type<K>(_: &K) -> String {
@@ -24,3 +62,148 @@ pub fn connection(id: String) -> String {
 }
 
 
+
+
+/******************************************************/
+//
+//  PAGINATION
+//
+/******************************************************/
+
+pub fn page_limit(args: &ListArgs) -> usize {
+    limit_clamp(args.limit)
+}
+
+pub fn limit_clamp(limit: Option<u64>) -> usize {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize
+}
+
+pub fn page_start(args: &ListArgs) -> Bound<String> {
+    match &args.start_after {
+        Some(key) => Bound::Excluded(key.clone()),
+        None => Bound::Unbounded,
+    }
+}
+
+/// Collects the entries that pass `keep`. At most `MAX_PAGE_SCAN` entries
+/// are visited per call, so a filtered page can hold fewer than `limit` items
+/// while `next` is still set; callers page until `next` is `None`. `next` is
+/// only set when another entry follows.
+pub fn paginate<V, T>(
+    entries: impl Iterator<Item = (String, V)>,
+    args: &ListArgs,
+    keep: impl Fn(&String, &V) -> bool,
+    to_item: impl Fn(String, V) -> T,
+) -> Page<T> {
+    let limit = page_limit(args);
+    let mut entries = entries.peekable();
+    let mut items: Vec<T> = Vec::new();
+    let mut scanned: u64 = 0;
+
+    while let Some((k, v)) = entries.next() {
+        scanned += 1;
+
+        if keep(&k, &v) {
+            items.push(to_item(k.clone(), v));
+        }
+
+        if items.len() == limit || scanned == MAX_PAGE_SCAN {
+            let next = entries.peek().is_some().then_some(k);
+            return Page { items, next };
+        }
+    }
+
+    Page { items, next: None }
+}
+
+pub fn name_matches(name: &str, args: &ListArgs) -> bool {
+    match &args.name_prefix {
+        Some(prefix) => name.starts_with(prefix.as_str()),
+        None => true,
+    }
+}
+
+pub fn active_matches(active: bool, args: &ListArgs) -> bool {
+    active || !args.active_only.unwrap_or(false)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn args(start_after: Option<&str>, limit: u64) -> ListArgs {
+        ListArgs {
+            start_after: start_after.map(|s| s.to_string()),
+            limit: Some(limit),
+            active_only: None,
+            name_prefix: None,
+            topic: None,
+        }
+    }
+
+    fn entries(count: u64) -> BTreeMap<String, u64> {
+        (0..count).map(|i| (format!("key-{:05}", i), i)).collect()
+    }
+
+    fn page_of(map: &BTreeMap<String, u64>, args: &ListArgs, keep: impl Fn(&String, &u64) -> bool) -> Page<u64> {
+        let range = map.range::<String, _>((page_start(args), Bound::Unbounded)).map(|(k, v)| (k.clone(), *v));
+        paginate(range, args, keep, |_, v| v)
+    }
+
+    #[test]
+    fn a_page_that_fills_exactly_at_the_end_has_no_next() {
+        let map = entries(3);
+        let page = page_of(&map, &args(None, 3), |_, _| true);
+
+        assert_eq!(page.items, vec![0, 1, 2]);
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn a_full_page_with_more_entries_points_at_its_last_key() {
+        let map = entries(5);
+        let page = page_of(&map, &args(None, 3), |_, _| true);
+
+        assert_eq!(page.items, vec![0, 1, 2]);
+        assert_eq!(page.next, Some("key-00002".to_string()));
+    }
+
+    #[test]
+    fn start_after_continues_behind_the_key() {
+        let map = entries(5);
+        let page = page_of(&map, &args(Some("key-00002"), 3), |_, _| true);
+
+        assert_eq!(page.items, vec![3, 4]);
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn filters_skip_entries_without_ending_the_page() {
+        let map = entries(10);
+        let page = page_of(&map, &args(None, 3), |_, v| v % 2 == 1);
+
+        assert_eq!(page.items, vec![1, 3, 5]);
+        assert_eq!(page.next, Some("key-00005".to_string()));
+    }
+
+    #[test]
+    fn a_scan_stops_after_max_page_scan_entries() {
+        let map = entries(MAX_PAGE_SCAN + 10);
+        let page = page_of(&map, &args(None, 3), |_, v| *v >= MAX_PAGE_SCAN);
+
+        assert!(page.items.is_empty());
+        assert_eq!(page.next, Some(format!("key-{:05}", MAX_PAGE_SCAN - 1)));
+
+        let page = page_of(&map, &args(page.next.as_deref(), 3), |_, v| *v >= MAX_PAGE_SCAN);
+        assert_eq!(page.items, vec![MAX_PAGE_SCAN, MAX_PAGE_SCAN + 1, MAX_PAGE_SCAN + 2]);
+    }
+
+    #[test]
+    fn limits_are_clamped() {
+        assert_eq!(limit_clamp(Some(0)), 1);
+        assert_eq!(limit_clamp(Some(10_000)), MAX_PAGE_SIZE as usize);
+        assert_eq!(limit_clamp(None), DEFAULT_PAGE_SIZE as usize);
+    }
+}
@@ -0,0 +1,219 @@
+use candid::Principal;
+use crate::types::{FieldError, RegistryError, ValidationCode};
+use crate::utils::principal_check;
+
+pub const MAX_ID_LEN: usize = 64;
+pub const MAX_NAME_LEN: usize = 64;
+pub const MAX_LABEL_LEN: usize = 128;
+pub const MAX_DESCRIPTION_LEN: usize = 1024;
+pub const MAX_CALLBACK_LEN: usize = 64;
+pub const MAX_TOPIC_NAMESPACES: usize = 64;
+
+
+/******************************************************/
+//
+//  VALIDATION
+//
+//  Every register/update endpoint collects the problems with
+//  its input into a list of field errors before anything is
+//  written
+//
+//  check_id            Record ID, or a reference to one
+//  check_name          Unique name used for lookups
+//  check_label         Free text display name
+//  check_description   Free text description
+//  check_callback      Candid method name of a subscriber
+//  check_principal     Canister ID of a subscriber or canister
+//  check_list_len      Number of items in a list field
+//  validation_result   Turn the collected errors into a result
+//
+/******************************************************/
+
+fn field_error(errors: &mut Vec<FieldError>, field: &str, code: ValidationCode, message: String) {
+    errors.push(FieldError {
+        field: field.to_string(),
+        code,
+        message,
+    });
+}
+
+fn check_length(errors: &mut Vec<FieldError>, field: &str, value: &str, max: usize) -> bool {
+    if value.len() > max {
+        field_error(errors, field, ValidationCode::TooLong, format!("{} must be at most {} bytes", field, max));
+        return false;
+    }
+    true
+}
+
+fn check_required(errors: &mut Vec<FieldError>, field: &str, value: &str) -> bool {
+    if value.trim().is_empty() {
+        field_error(errors, field, ValidationCode::Required, format!("{} is required", field));
+        return false;
+    }
+    true
+}
+
+fn check_no_control(errors: &mut Vec<FieldError>, field: &str, value: &str, allow_newlines: bool) {
+    let invalid = value.chars().any(|c| c.is_control() && !(allow_newlines && (c == '\n' || c == '\t')));
+
+    if invalid {
+        field_error(errors, field, ValidationCode::InvalidCharacters, format!("{} contains control characters", field));
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'
+}
+
+/// Endpoints that generate an ID when none is given only check a non-empty
+/// one.
+pub fn check_id(errors: &mut Vec<FieldError>, field: &str, value: &str) {
+    if !check_required(errors, field, value) || !check_length(errors, field, value, MAX_ID_LEN) {
+        return;
+    }
+
+    if !value.chars().all(is_name_char) {
+        field_error(errors, field, ValidationCode::InvalidCharacters, format!("{} may only contain letters, digits, '-', '_' and '.'", field));
+    }
+}
+
+/// Names are used as lookup keys and in routing, so they are limited to
+/// letters, digits, '-', '_' and '.'.
+pub fn check_name(errors: &mut Vec<FieldError>, field: &str, value: &str) {
+    if !check_required(errors, field, value) || !check_length(errors, field, value, MAX_NAME_LEN) {
+        return;
+    }
+
+    if !value.chars().all(is_name_char) {
+        field_error(errors, field, ValidationCode::InvalidCharacters, format!("{} may only contain letters, digits, '-', '_' and '.'", field));
+    }
+}
+
+pub fn check_label(errors: &mut Vec<FieldError>, field: &str, value: &str) {
+    if check_length(errors, field, value, MAX_LABEL_LEN) {
+        check_no_control(errors, field, value, false);
+    }
+}
+
+pub fn check_description(errors: &mut Vec<FieldError>, field: &str, value: &str) {
+    if check_length(errors, field, value, MAX_DESCRIPTION_LEN) {
+        check_no_control(errors, field, value, true);
+    }
+}
+
+/// The queue calls the callback as a Candid method, so it has to be a
+/// plain identifier.
+pub fn check_callback(errors: &mut Vec<FieldError>, field: &str, value: &str) {
+    if !check_required(errors, field, value) || !check_length(errors, field, value, MAX_CALLBACK_LEN) {
+        return;
+    }
+
+    let mut chars = value.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !valid {
+        field_error(errors, field, ValidationCode::InvalidCharacters, format!("{} must start with a letter or '_' and contain only letters, digits and '_'", field));
+    }
+}
+
+pub fn check_principal(errors: &mut Vec<FieldError>, field: &str, value: &Principal) {
+    if principal_check(value).is_err() {
+        field_error(errors, field, ValidationCode::InvalidPrincipal, format!("{} cannot be the anonymous principal", field));
+    }
+}
+
+pub fn check_list_len(errors: &mut Vec<FieldError>, field: &str, len: usize, max: usize) {
+    if len > max {
+        field_error(errors, field, ValidationCode::TooMany, format!("{} can have at most {} items", field, max));
+    }
+}
+
+pub fn validation_result(errors: Vec<FieldError>) -> Result<(), RegistryError> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(RegistryError::Invalid(errors))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Check = fn(&mut Vec<FieldError>, &str, &str);
+
+    /// Runs a check and returns the codes it reported.
+    fn codes(check: Check, value: &str) -> Vec<ValidationCode> {
+        let mut errors = Vec::new();
+        check(&mut errors, "field", value);
+
+        errors.iter().map(|e| e.code).collect()
+    }
+
+    #[test]
+    fn text_checks() {
+        use ValidationCode::*;
+
+        let cases: Vec<(&str, Check, String, Vec<ValidationCode>)> = vec![
+            ("id", check_id, "sub-1.a_b".to_string(), vec![]),
+            ("id empty", check_id, "".to_string(), vec![Required]),
+            ("id blank", check_id, "  ".to_string(), vec![Required]),
+            ("id too long", check_id, "i".repeat(MAX_ID_LEN + 1), vec![TooLong]),
+            ("id at the limit", check_id, "i".repeat(MAX_ID_LEN), vec![]),
+            ("id characters", check_id, "sub 1".to_string(), vec![InvalidCharacters]),
+            ("name", check_name, "orders.eu-1".to_string(), vec![]),
+            ("name empty", check_name, "".to_string(), vec![Required]),
+            ("name too long", check_name, "n".repeat(MAX_NAME_LEN + 1), vec![TooLong]),
+            ("name characters", check_name, "orders/eu".to_string(), vec![InvalidCharacters]),
+            ("label", check_label, "Orders (EU)".to_string(), vec![]),
+            ("label empty", check_label, "".to_string(), vec![]),
+            ("label too long", check_label, "l".repeat(MAX_LABEL_LEN + 1), vec![TooLong]),
+            ("label newline", check_label, "a\nb".to_string(), vec![InvalidCharacters]),
+            ("description newline", check_description, "a\n\tb".to_string(), vec![]),
+            ("description control", check_description, "a\u{7}b".to_string(), vec![InvalidCharacters]),
+            ("description too long", check_description, "d".repeat(MAX_DESCRIPTION_LEN + 1), vec![TooLong]),
+            ("callback", check_callback, "_on_message2".to_string(), vec![]),
+            ("callback empty", check_callback, "".to_string(), vec![Required]),
+            ("callback too long", check_callback, "c".repeat(MAX_CALLBACK_LEN + 1), vec![TooLong]),
+            ("callback leading digit", check_callback, "2fast".to_string(), vec![InvalidCharacters]),
+            ("callback dash", check_callback, "on-message".to_string(), vec![InvalidCharacters]),
+        ];
+
+        for (case, check, value, expected) in cases.iter() {
+            assert_eq!(&codes(*check, value), expected, "{}", case);
+        }
+    }
+
+    #[test]
+    fn principal_check_rejects_the_anonymous_principal() {
+        let mut errors = Vec::new();
+        check_principal(&mut errors, "canister_id", &Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap());
+        assert!(errors.is_empty());
+
+        check_principal(&mut errors, "canister_id", &Principal::anonymous());
+        assert_eq!(errors.iter().map(|e| e.code).collect::<Vec<_>>(), vec![ValidationCode::InvalidPrincipal]);
+    }
+
+    #[test]
+    fn list_len_check() {
+        let mut errors = Vec::new();
+        check_list_len(&mut errors, "namespaces", MAX_TOPIC_NAMESPACES, MAX_TOPIC_NAMESPACES);
+        assert!(errors.is_empty());
+
+        check_list_len(&mut errors, "namespaces", MAX_TOPIC_NAMESPACES + 1, MAX_TOPIC_NAMESPACES);
+        assert_eq!(errors.iter().map(|e| e.code).collect::<Vec<_>>(), vec![ValidationCode::TooMany]);
+    }
+
+    #[test]
+    fn errors_carry_the_field_and_turn_into_invalid() {
+        let mut errors = Vec::new();
+        check_name(&mut errors, "name", "");
+        check_id(&mut errors, "topic", "");
+
+        assert_eq!(errors.iter().map(|e| e.field.as_str()).collect::<Vec<_>>(), vec!["name", "topic"]);
+        assert!(matches!(validation_result(errors), Err(RegistryError::Invalid(e)) if e.len() == 2));
+        assert!(validation_result(Vec::new()).is_ok());
+    }
+}